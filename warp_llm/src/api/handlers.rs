@@ -0,0 +1,629 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures_util::Stream;
+use tracing::Instrument;
+use tokio::sync::mpsc::UnboundedReceiver;
+use warp::reply::{Reply, Response};
+use warp::sse::Event;
+
+use super::models::{
+    ChatChoice, ChatChunkChoice, ChatCompletionChunk, ChatCompletionRequest, ChatCompletionResponse,
+    ChatDelta, ChatRequest, ChatResponse, CompletionUsage, CreateSessionRequest, ModelCard, ModelList,
+    ModelInfo, ModelStatus, Prompt, SessionInfo, SessionMessageRequest, SessionMessageResponse, StreamEvent,
+    SwapRequest,
+};
+use super::state::AppState;
+use crate::llm::auth::{AuthError, Caller};
+use crate::llm::cancel::{CancelOnDrop, Ticket};
+use crate::llm::chat::{self, ChatMessage, Role};
+use crate::llm::engine::{EngineHandle, GenerateRequest, OnQueued, OnToken, OnUsage};
+use crate::llm::llm::Args;
+use crate::llm::error::LlmError;
+use crate::llm::llm_ops::{self, FinishReason, Generation};
+use crate::llm::metrics::METRICS;
+use crate::llm::params::{GenerationConfig, Truncation};
+use crate::llm::registry::ModelEntry;
+use crate::llm::sessions::{KvSnapshot, SessionError};
+use crate::{Conflict, NotFound};
+
+
+// Turns the receiving end of a generation channel into an SSE body
+fn sse_stream<T: Send + 'static>(
+    rx: UnboundedReceiver<T>,
+) -> impl Stream<Item = T> + Send + 'static {
+    futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|item| (item, rx))
+    })
+}
+
+fn llm_error(e: LlmError) -> warp::Rejection {
+    if !matches!(e, LlmError::InvalidParams(_)) {
+        eprintln!("{}", e);
+    }
+    warp::reject::custom(e)
+}
+
+fn auth_error(e: AuthError) -> warp::Rejection {
+    warp::reject::custom(e)
+}
+
+// The caller of every endpoint but the probes and /metrics
+pub async fn authenticate(
+    authorization: Option<String>,
+    remote: Option<SocketAddr>,
+    state: AppState,
+) -> Result<Caller, warp::Rejection> {
+    match &state.keys {
+        Some(keys) => keys.authenticate(authorization.as_deref()).map_err(auth_error),
+        None => Ok(Caller::anonymous(remote.is_some_and(|addr| addr.ip().is_loopback()))),
+    }
+}
+
+// Runs a generation job on a task of its own and forwards each fragment as a `token` event
+// as soon as it is decoded, followed by a `done` event with the usage stats. While the job waits
+// for the model, `queued` events report its position; a client that disconnects meanwhile
+// takes it off the queue.
+fn stream_job<F, Fut>(job: F) -> Response
+where
+    F: FnOnce(OnToken, Option<OnQueued>) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<Generation, LlmError>> + Send + 'static,
+{
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<StreamEvent>();
+    let token_tx = tx.clone();
+    let on_token: OnToken = Box::new(move |t| token_tx.send(StreamEvent::Token(t.to_string())).is_ok());
+    let queue_tx = tx.clone();
+    let mut last_position = 0;
+    let on_queued: OnQueued = Box::new(move |position| {
+        if position == last_position {
+            return !queue_tx.is_closed();
+        }
+        last_position = position;
+        queue_tx.send(StreamEvent::Queued(position)).is_ok()
+    });
+    tokio::spawn(async move {
+        let result = job(on_token, Some(on_queued)).await;
+        let _ = match result {
+            Ok(generation) => tx.send(StreamEvent::Done(generation)),
+            Err(e) => {
+                eprintln!("{}", e);
+                tx.send(StreamEvent::Error(e))
+            }
+        };
+    }.instrument(tracing::Span::current()));
+    let events = futures_util::StreamExt::map(sse_stream(rx), StreamEvent::into_sse);
+    warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response()
+}
+
+// Charges the tokens of a generation to `caller` when the engine is done with it, also when
+// the client went away or the generation failed
+fn charge(caller: &Caller) -> OnUsage {
+    let caller = caller.clone();
+    Box::new(move |usage| caller.charge(usage))
+}
+
+// A fresh (not session) generation of `prompt` on the engine, charged to `caller`
+async fn generate_once(
+    engine: &EngineHandle,
+    config: GenerationConfig,
+    prompt: RenderedPrompt,
+    on_token: OnToken,
+    on_queued: Option<OnQueued>,
+    ticket: &Ticket,
+    caller: &Caller,
+) -> Result<Generation, LlmError> {
+    let request = GenerateRequest {
+        request_id: ticket.id.clone(),
+        prompt: prompt.text,
+        config,
+        kv: KvSnapshot::default(),
+        on_token,
+        on_queued,
+        on_usage: Some(charge(caller)),
+        cancel: ticket.token.clone(),
+    };
+    let (mut generation, _) = engine.generate(request).await?;
+    note_dropped(&mut generation, prompt.dropped_messages);
+    Ok(generation)
+}
+
+// `ticket` stays registered until the generation is over
+fn stream_generation(
+    engine: EngineHandle,
+    config: GenerationConfig,
+    prompt: RenderedPrompt,
+    ticket: Ticket,
+    caller: Caller,
+) -> Response {
+    stream_job(move |on_token, on_queued| async move {
+        generate_once(&engine, config, prompt, on_token, on_queued, &ticket, &caller).await
+    })
+}
+
+// A prompt rendered with the chat template and how many messages had to be left out of it
+struct RenderedPrompt {
+    text: String,
+    dropped_messages: usize,
+}
+
+fn note_dropped(generation: &mut Generation, dropped_messages: usize) {
+    if dropped_messages > 0 {
+        generation.truncated.get_or_insert_with(Default::default).messages = dropped_messages;
+    }
+}
+
+// Renders the conversation; with `"truncation": "oldest_messages"` the oldest exchanges are
+// left out (the system prompt stays) until the prompt fits into the context window
+fn render_chat(args: &Args, engine: &EngineHandle, messages: &[ChatMessage], config: &GenerationConfig)
+    -> Result<RenderedPrompt, LlmError> {
+    let template = engine.model().template;
+    let mut prompt = RenderedPrompt { text: llm_ops::format_chat(args, template, messages), dropped_messages: 0 };
+    if config.truncation != Truncation::OldestMessages {
+        return Ok(prompt);
+    }
+    let (system, turns) = match messages.split_first() {
+        Some((first, rest)) if first.role == Role::System => (Some(first), rest),
+        _ => (None, messages),
+    };
+    let budget = config.prompt_budget(engine.context_length());
+    while engine.count_tokens(&prompt.text)? > budget {
+        // the kept conversation has to open with a user turn
+        let start = prompt.dropped_messages;
+        let Some(next) = turns[start + 1..].iter().position(|m| m.role == Role::User) else {
+            break;
+        };
+        prompt.dropped_messages = start + 1 + next;
+        let kept: Vec<ChatMessage> = system.into_iter().chain(&turns[prompt.dropped_messages..]).cloned().collect();
+        prompt.text = llm_ops::format_chat(args, template, &kept);
+    }
+    Ok(prompt)
+}
+
+fn wants_stream(stream: Option<bool>, accept: Option<String>) -> bool {
+    stream.unwrap_or(false) || accept.is_some_and(|a| a.contains("text/event-stream"))
+}
+
+// POST /generate; streams when the client asks for `text/event-stream` or sets `"stream": true`
+pub async fn generate(
+    caller: Caller,
+    mut prompt: Prompt,
+    accept: Option<String>,
+    state: AppState,
+) -> Result<Response, warp::Rejection> {
+    let config = prompt.params.resolve(&state.args).map_err(llm_error)?;
+    // before the engine, so a key over its limits cannot load (and evict) models
+    caller.admit().map_err(auth_error)?;
+    let engine = state.models.engine(prompt.model.as_deref()).await.map_err(llm_error)?;
+    let prompt_str = llm_ops::format_prompt(&state.args, engine.model().template, Some(&prompt.prompt));
+    let prompt_str = RenderedPrompt { text: prompt_str, dropped_messages: 0 };
+
+    // dropped with this handler, so a client that disconnects cancels the generation
+    let ticket = state.in_flight.start(caller.owner());
+    let request_id = ticket.id.clone();
+    if wants_stream(prompt.stream, accept) {
+        let response = stream_generation(engine, config, prompt_str, ticket, caller);
+        return Ok(warp::reply::with_header(response, "x-request-id", request_id).into_response());
+    }
+
+    prompt.id = Some(request_id.clone());
+    prompt.model = Some(engine.model().id.clone());
+    match generate_once(&engine, config, prompt_str, Box::new(|_| true), None, &ticket, &caller).await {
+        Ok(generation) => {
+            prompt.generated = Some(generation.text);
+            prompt.finish_reason = Some(generation.finish_reason);
+            prompt.usage = Some(generation.usage);
+            prompt.truncated = generation.truncated;
+            Ok(warp::reply::with_header(warp::reply::json(&prompt), "x-request-id", request_id).into_response())
+        }
+        Err(e) => Err(llm_error(e)),
+    }
+}
+
+// POST /generate/{id}/cancel; the request ends with what it generated so far
+// (other keys get 404 for requests they did not start)
+pub async fn cancel_generation(id: String, caller: Caller, state: AppState) -> Result<Response, warp::Rejection> {
+    if state.in_flight.cancel(&id, caller.owner()) {
+        Ok(warp::http::StatusCode::NO_CONTENT.into_response())
+    } else {
+        Err(warp::reject::custom(NotFound))
+    }
+}
+
+// POST /chat; a conversation in, the assistant reply and the extended conversation out
+pub async fn chat(
+    caller: Caller,
+    request: ChatRequest,
+    accept: Option<String>,
+    state: AppState,
+) -> Result<Response, warp::Rejection> {
+    chat::validate(&request.messages).map_err(LlmError::InvalidParams).map_err(llm_error)?;
+    let config = request.params.resolve(&state.args).map_err(llm_error)?;
+    caller.admit().map_err(auth_error)?;
+    let engine = state.models.engine(request.model.as_deref()).await.map_err(llm_error)?;
+    let prompt_str = render_chat(&state.args, &engine, &request.messages, &config).map_err(llm_error)?;
+
+    let ticket = state.in_flight.start(caller.owner());
+    if wants_stream(request.stream, accept) {
+        return Ok(stream_generation(engine, config, prompt_str, ticket, caller));
+    }
+
+    let generation = generate_once(&engine, config, prompt_str, Box::new(|_| true), None, &ticket, &caller).await
+        .map_err(llm_error)?;
+    let message = ChatMessage::new(Role::Assistant, generation.text);
+    let mut messages = request.messages;
+    messages.push(message.clone());
+    let response = ChatResponse {
+        model: engine.model().id.clone(),
+        message,
+        messages,
+        finish_reason: generation.finish_reason,
+        usage: generation.usage,
+        truncated: generation.truncated,
+    };
+    Ok(warp::reply::json(&response).into_response())
+}
+
+
+fn session_error(e: SessionError) -> warp::Rejection {
+    match e {
+        SessionError::NotFound => warp::reject::custom(NotFound),
+        SessionError::Busy => warp::reject::custom(Conflict {
+            message: "the session is already generating a reply".to_string(),
+        }),
+    }
+}
+
+// POST /sessions; sessions belong to the key that creates them, other keys get 404 for them
+pub async fn create_session(
+    caller: Caller,
+    request: CreateSessionRequest,
+    state: AppState,
+) -> Result<Response, warp::Rejection> {
+    let model = state.models.model_id(request.model.as_deref()).map_err(llm_error)?.to_string();
+    // a session holds kv cache, so creating one counts against the key's requests
+    caller.admit().map_err(auth_error)?;
+    let messages: Vec<_> = request.system.into_iter().map(|s| ChatMessage::new(Role::System, s)).collect();
+    let id = state.sessions.lock().unwrap().create(caller.owner(), model, messages);
+    let info = session_info(&state, id, &caller)?;
+    Ok(warp::reply::with_status(warp::reply::json(&info), warp::http::StatusCode::CREATED).into_response())
+}
+
+fn session_info(state: &AppState, id: String, caller: &Caller) -> Result<SessionInfo, warp::Rejection> {
+    let mut sessions = state.sessions.lock().unwrap();
+    let session = sessions.get(&id, caller.owner()).ok_or_else(|| warp::reject::custom(NotFound))?;
+    Ok(SessionInfo {
+        model: session.model.clone(),
+        messages: session.messages.clone(),
+        cached_tokens: session.kv.tokens.len(),
+        cache_bytes: session.kv.size_in_bytes(),
+        idle_secs: session.last_used.elapsed().as_secs(),
+        id,
+    })
+}
+
+// GET /sessions/{id}
+pub async fn get_session(id: String, caller: Caller, state: AppState) -> Result<Response, warp::Rejection> {
+    let info = session_info(&state, id, &caller)?;
+    Ok(warp::reply::json(&info).into_response())
+}
+
+// POST /sessions/{id}/fork; a new session with the same conversation, sharing its kv cache
+// blocks until either one writes to them
+pub async fn fork_session(id: String, caller: Caller, state: AppState) -> Result<Response, warp::Rejection> {
+    caller.admit().map_err(auth_error)?;
+    let id = state.sessions.lock().unwrap().fork(&id, caller.owner()).map_err(session_error)?;
+    let info = session_info(&state, id, &caller)?;
+    Ok(warp::reply::with_status(warp::reply::json(&info), warp::http::StatusCode::CREATED).into_response())
+}
+
+// DELETE /sessions/{id}
+pub async fn delete_session(id: String, caller: Caller, state: AppState) -> Result<Response, warp::Rejection> {
+    if state.sessions.lock().unwrap().remove(&id, caller.owner()) {
+        Ok(warp::http::StatusCode::NO_CONTENT.into_response())
+    } else {
+        Err(warp::reject::custom(NotFound))
+    }
+}
+
+// POST /sessions/{id}/messages; one user turn, generated on top of the kv cache of the previous turns
+pub async fn session_message(
+    id: String,
+    caller: Caller,
+    request: SessionMessageRequest,
+    accept: Option<String>,
+    state: AppState,
+) -> Result<Response, warp::Rejection> {
+    let config = request.params.resolve(&state.args).map_err(llm_error)?;
+    caller.admit().map_err(auth_error)?;
+    let (model, mut messages, kv) = state.sessions.lock().unwrap().begin_turn(&id, caller.owner())
+        .map_err(session_error)?;
+    messages.push(ChatMessage::new(Role::User, request.content));
+
+    let session_id = id.clone();
+    let ticket = state.in_flight.start(caller.owner());
+    let cancel = ticket.token.clone();
+    let job = move |on_token: OnToken, on_queued: Option<OnQueued>| async move {
+        let engine = state.models.engine(Some(&model)).await;
+        let prompt = engine.and_then(|engine| {
+            let prompt = render_chat(&state.args, &engine, &messages, &config)?;
+            Ok((engine, prompt))
+        });
+        let result = match prompt {
+            Ok((engine, prompt)) => {
+                let request = GenerateRequest {
+                    request_id: ticket.id.clone(),
+                    prompt: prompt.text,
+                    config,
+                    kv,
+                    on_token,
+                    on_queued,
+                    on_usage: Some(charge(&caller)),
+                    cancel: ticket.token.clone(),
+                };
+                engine.generate(request).await.map(|(mut generation, kv)| {
+                    note_dropped(&mut generation, prompt.dropped_messages);
+                    (generation, kv)
+                })
+            }
+            Err(e) => Err(e),
+        };
+        let (result, kv) = match result {
+            Ok((generation, kv)) => {
+                messages.push(ChatMessage::new(Role::Assistant, generation.text.clone()));
+                (Ok(generation), kv)
+            }
+            Err(e) => {
+                // keep the conversation as it was and let the next turn start from scratch
+                messages.pop();
+                (Err(e), KvSnapshot::default())
+            }
+        };
+        state.sessions.lock().unwrap().end_turn(&session_id, messages, kv);
+        result
+    };
+
+    if wants_stream(request.stream, accept) {
+        return Ok(stream_job(job));
+    }
+    // on a task of its own, so that the session gets its turn back even when the client hangs up,
+    // which only cancels the generation
+    let _cancel = CancelOnDrop(cancel);
+    let turn = tokio::spawn(job(Box::new(|_| true), None).instrument(tracing::Span::current()));
+    let generation = turn
+        .await
+        .map_err(|e| LlmError::Inference(format!("the session turn failed: {}", e)))
+        .and_then(|result| result)
+        .map_err(llm_error)?;
+    let response = SessionMessageResponse {
+        session_id: id,
+        message: ChatMessage::new(Role::Assistant, generation.text),
+        finish_reason: generation.finish_reason,
+        usage: generation.usage,
+        truncated: generation.truncated,
+    };
+    Ok(warp::reply::json(&response).into_response())
+}
+
+
+// OpenAI has no separate reasons for stop sequences, cancellation and timeouts
+fn openai_finish_reason(finish_reason: FinishReason) -> FinishReason {
+    match finish_reason {
+        FinishReason::StopSequence | FinishReason::Cancelled => FinishReason::Stop,
+        FinishReason::Timeout => FinishReason::Length,
+        other => other,
+    }
+}
+
+fn completion_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    format!("chatcmpl-{:x}{:04x}", unix_time(), COUNTER.fetch_add(1, Ordering::Relaxed) & 0xffff)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+// POST /v1/chat/completions, OpenAI request and response shapes
+pub async fn chat_completions(
+    caller: Caller,
+    request: ChatCompletionRequest,
+    state: AppState,
+) -> Result<Response, warp::Rejection> {
+    chat::validate(&request.messages).map_err(LlmError::InvalidParams).map_err(llm_error)?;
+    let config = request.params.resolve(&state.args).map_err(llm_error)?;
+    caller.admit().map_err(auth_error)?;
+    let engine = state.models.engine(request.model.as_deref()).await.map_err(llm_error)?;
+    let prompt_str = render_chat(&state.args, &engine, &request.messages, &config).map_err(llm_error)?;
+    let id = completion_id();
+    let ticket = state.in_flight.start(caller.owner());
+    let created = unix_time();
+    let model_name = engine.model().id.clone();
+
+    if request.stream.unwrap_or(false) {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<Result<Event, serde_json::Error>>();
+        let chunk = move |delta: ChatDelta, finish_reason, usage| ChatCompletionChunk {
+            id: id.clone(),
+            object: "chat.completion.chunk",
+            created,
+            model: model_name.clone(),
+            choices: vec![ChatChunkChoice { index: 0, delta, finish_reason }],
+            usage,
+        };
+        tokio::spawn(async move {
+            let role = ChatDelta { role: Some(Role::Assistant), content: None };
+            let _ = tx.send(Event::default().json_data(chunk(role, None, None)));
+            let token_tx = tx.clone();
+            let token_chunk = chunk.clone();
+            let on_token: OnToken = Box::new(move |t| {
+                let delta = ChatDelta { role: None, content: Some(t.to_string()) };
+                token_tx.send(Event::default().json_data(token_chunk(delta, None, None))).is_ok()
+            });
+            // OpenAI chunks have no place for the queue position, only leave the queue on disconnect
+            let queue_tx = tx.clone();
+            let on_queued: OnQueued = Box::new(move |_| !queue_tx.is_closed());
+            match generate_once(&engine, config, prompt_str, on_token, Some(on_queued), &ticket, &caller).await {
+                Ok(generation) => {
+                    let usage = Some(CompletionUsage::from(&generation.usage));
+                    let finish_reason = openai_finish_reason(generation.finish_reason);
+                    let done = chunk(ChatDelta::default(), Some(finish_reason), usage);
+                    let _ = tx.send(Event::default().json_data(done));
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    let error = serde_json::json!({ "error": { "message": e.to_string(), "code": e.code() } });
+                    let _ = tx.send(Event::default().json_data(error));
+                }
+            }
+            let _ = tx.send(Ok(Event::default().data("[DONE]")));
+        }.instrument(tracing::Span::current()));
+        return Ok(warp::sse::reply(sse_stream(rx)).into_response());
+    }
+
+    let generation = generate_once(&engine, config, prompt_str, Box::new(|_| true), None, &ticket, &caller).await
+        .map_err(llm_error)?;
+    let response = ChatCompletionResponse {
+        id,
+        object: "chat.completion",
+        created,
+        model: model_name,
+        choices: vec![ChatChoice {
+            index: 0,
+            message: ChatMessage::new(Role::Assistant, generation.text),
+            finish_reason: openai_finish_reason(generation.finish_reason),
+        }],
+        usage: CompletionUsage::from(&generation.usage),
+    };
+    Ok(warp::reply::json(&response).into_response())
+}
+
+// GET /v1/models, the registered models in the OpenAI list format
+pub async fn list_models(_caller: Caller, state: AppState) -> Result<Response, warp::Rejection> {
+    let data = state.models.list().into_iter().map(|(entry, loaded)| model_card(&state, &entry, loaded)).collect();
+    Ok(warp::reply::json(&ModelList { object: "list", data }).into_response())
+}
+
+fn model_card(state: &AppState, entry: &ModelEntry, loaded: bool) -> ModelCard {
+    ModelCard {
+        id: entry.id.clone(),
+        object: "model",
+        created: 0,
+        owned_by: "warp_llm",
+        loaded,
+        default: entry.id == state.models.default_model(),
+    }
+}
+
+// GET /v1/models/{id}; the card plus what the GGUF header says about the model
+pub async fn model_info(id: String, _caller: Caller, state: AppState) -> Result<Response, warp::Rejection> {
+    let entry = state.models.entry(&id).ok_or_else(|| llm_error(LlmError::UnknownModel(id.clone())))?;
+    let path = entry.gguf.clone();
+    let gguf = tokio::task::spawn_blocking(move || llm_ops::gguf_info(&path))
+        .await
+        .map_err(|e| llm_error(LlmError::ModelLoad(e.to_string())))?
+        .map_err(llm_error)?;
+    let info = ModelInfo {
+        card: model_card(&state, &entry, state.models.is_loaded(&id)),
+        template: entry.template,
+        gguf,
+    };
+    Ok(warp::reply::json(&info).into_response())
+}
+
+fn model_status_of(state: &AppState, id: &str) -> Result<ModelStatus, warp::Rejection> {
+    let entry = state.models.entry(id).ok_or_else(|| llm_error(LlmError::UnknownModel(id.to_string())))?;
+    Ok(ModelStatus {
+        entry,
+        loaded: state.models.is_loaded(id),
+        swap: state.models.swap_status(id),
+    })
+}
+
+// GET /admin/models/{id}
+pub async fn model_status(id: String, caller: Caller, state: AppState) -> Result<Response, warp::Rejection> {
+    caller.require_admin().map_err(auth_error)?;
+    let status = model_status_of(&state, &id)?;
+    Ok(warp::reply::json(&status).into_response())
+}
+
+// POST /admin/models/{id}/swap; loads the new files in the background and answers right away,
+// poll GET /admin/models/{id} for the outcome
+pub async fn swap_model(
+    id: String,
+    caller: Caller,
+    request: SwapRequest,
+    state: AppState,
+) -> Result<Response, warp::Rejection> {
+    caller.require_admin().map_err(auth_error)?;
+    let mut entry = state.models.entry(&id).ok_or_else(|| llm_error(LlmError::UnknownModel(id.clone())))?;
+    if let Some(gguf) = request.gguf {
+        entry.gguf = gguf;
+    }
+    if let Some(tokenizer) = request.tokenizer {
+        entry.tokenizer = tokenizer;
+    }
+    if let Some(template) = request.template {
+        entry.template = template;
+    }
+    if request.eos_token.is_some() {
+        entry.eos_token = request.eos_token;
+    }
+    for path in [&entry.gguf, &entry.tokenizer] {
+        if !path.is_file() {
+            return Err(llm_error(LlmError::InvalidParams(format!("{} is not a file", path.display()))));
+        }
+    }
+
+    if !state.models.swap(entry).map_err(llm_error)? {
+        return Err(warp::reject::custom(Conflict {
+            message: format!("a swap of model `{}` is still loading", id),
+        }));
+    }
+    let status = model_status_of(&state, &id)?;
+    Ok(warp::reply::with_status(warp::reply::json(&status), warp::http::StatusCode::ACCEPTED).into_response())
+}
+
+
+// GET /v1/usage; the limits of the caller's key, what is left of them and what it used so far
+pub async fn usage(caller: Caller) -> Result<Response, warp::Rejection> {
+    let key = caller.key().ok_or_else(|| warp::reject::custom(NotFound))?;
+    Ok(warp::reply::json(&key.report()).into_response())
+}
+
+// GET /admin/usage; the same for every key
+pub async fn all_usage(caller: Caller, state: AppState) -> Result<Response, warp::Rejection> {
+    caller.require_admin().map_err(auth_error)?;
+    let keys = state.keys.as_ref().ok_or_else(|| warp::reject::custom(NotFound))?;
+    Ok(warp::reply::json(&keys.reports()).into_response())
+}
+
+
+// GET /metrics; gauges that belong to no engine are taken at scrape time
+pub async fn metrics(state: AppState) -> Result<Response, warp::Rejection> {
+    let session_bytes = state.sessions.lock().unwrap().cache_bytes();
+    METRICS.session_cache_bytes.set(session_bytes as i64);
+    let loaded = state.models.list().iter().filter(|(_, loaded)| *loaded).count();
+    METRICS.loaded_models.set(loaded as i64);
+    let body = METRICS.render();
+    Ok(warp::reply::with_header(body, "content-type", "text/plain; version=0.0.4").into_response())
+}
+
+// GET /healthz; the process is up and serving
+pub async fn healthz() -> Result<Response, warp::Rejection> {
+    Ok(warp::reply::json(&serde_json::json!({ "status": "ok" })).into_response())
+}
+
+// GET /readyz; 503 until the default model is loaded and its warm-up generation went through
+pub async fn readyz(state: AppState) -> Result<Response, warp::Rejection> {
+    let ready = state.ready.load(Ordering::Relaxed);
+    let body = serde_json::json!({
+        "status": if ready { "ready" } else { "starting" },
+        "model": state.models.default_model(),
+    });
+    let status = match ready {
+        true => warp::http::StatusCode::OK,
+        false => warp::http::StatusCode::SERVICE_UNAVAILABLE,
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&body), status).into_response())
+}
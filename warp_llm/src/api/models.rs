@@ -0,0 +1,252 @@
+use std::path::PathBuf;
+
+use serde_derive::{Deserialize, Serialize};
+use warp::sse::Event;
+
+use crate::llm::chat::{ChatMessage, ChatTemplate, Role};
+use crate::llm::error::LlmError;
+use crate::llm::llm_ops::{FinishReason, Generation, GgufInfo, Truncated, Usage};
+use crate::llm::params::GenerationParams;
+use crate::llm::pool::SwapStatus;
+use crate::llm::registry::ModelEntry;
+
+
+// POST /generate  {"prompt":"Who are you?","temperature":0}
+#[derive(Deserialize, Serialize)]
+pub struct Prompt {
+    /// Set on the response (and sent as `x-request-id`); `POST /generate/{id}/cancel` stops the request.
+    pub id: Option<String>,
+    pub prompt: String,
+    /// A registered model id; the default model when left out. Filled in on the response.
+    pub model: Option<String>,
+    /// temperature, top_k, top_p, seed, max_tokens, repeat_penalty, repeat_last_n, stop, stop_token_ids
+    #[serde(flatten)]
+    pub params: GenerationParams,
+    pub generated: Option<String>,
+    pub finish_reason: Option<FinishReason>,
+    /// Stream the completion as Server-Sent Events instead of a single JSON body.
+    pub stream: Option<bool>,
+    pub usage: Option<Usage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncated: Option<Truncated>,
+}
+
+// POST /chat  {"messages":[{"role":"system","content":"Answer briefly."},{"role":"user","content":"Who are you?"}]}
+#[derive(Deserialize)]
+pub struct ChatRequest {
+    pub model: Option<String>,
+    pub messages: Vec<ChatMessage>,
+    #[serde(flatten)]
+    pub params: GenerationParams,
+    pub stream: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct ChatResponse {
+    pub model: String,
+    pub message: ChatMessage,
+    /// The conversation including the reply, to be sent back with the next user message.
+    pub messages: Vec<ChatMessage>,
+    pub finish_reason: FinishReason,
+    pub usage: Usage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncated: Option<Truncated>,
+}
+
+// POST /sessions  {"model":"0.5b","system":"Answer briefly."}
+#[derive(Deserialize)]
+pub struct CreateSessionRequest {
+    /// The model every turn of the session runs on; the default model when left out.
+    pub model: Option<String>,
+    pub system: Option<String>,
+}
+
+// GET /sessions/{id}, also the reply of POST /sessions
+#[derive(Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    /// Tokens of the conversation whose kv cache is kept on the server.
+    pub cached_tokens: usize,
+    pub cache_bytes: usize,
+    pub idle_secs: u64,
+}
+
+// POST /sessions/{id}/messages  {"content":"Who are you?"}
+#[derive(Deserialize)]
+pub struct SessionMessageRequest {
+    pub content: String,
+    #[serde(flatten)]
+    pub params: GenerationParams,
+    pub stream: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct SessionMessageResponse {
+    pub session_id: String,
+    pub message: ChatMessage,
+    pub finish_reason: FinishReason,
+    pub usage: Usage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncated: Option<Truncated>,
+}
+
+// GET /v1/models
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelList {
+    pub object: &'static str,
+    pub data: Vec<ModelCard>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelCard {
+    pub id: String,
+    pub object: &'static str,
+    pub created: u64,
+    pub owned_by: &'static str,
+    /// Whether the weights are in memory right now; others are loaded on their first request.
+    pub loaded: bool,
+    /// The model serving requests that do not name one.
+    pub default: bool,
+}
+
+// GET /v1/models/{id}
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelInfo {
+    #[serde(flatten)]
+    pub card: ModelCard,
+    pub template: ChatTemplate,
+    pub gguf: GgufInfo,
+}
+
+// POST /admin/models/{id}/swap  {"gguf":"/models/qwen2.5-coder-14b-instruct-q4_0.gguf"}
+// Fields left out keep their current value.
+#[derive(Deserialize)]
+pub struct SwapRequest {
+    pub gguf: Option<PathBuf>,
+    pub tokenizer: Option<PathBuf>,
+    pub template: Option<ChatTemplate>,
+    pub eos_token: Option<String>,
+}
+
+// GET /admin/models/{id}, also the reply of a swap request
+#[derive(Serialize)]
+pub struct ModelStatus {
+    #[serde(flatten)]
+    pub entry: ModelEntry,
+    pub loaded: bool,
+    /// The last hot swap of this model, if any.
+    pub swap: Option<SwapStatus>,
+}
+
+// Messages sent from the blocking generation task to the SSE response of /generate and /chat
+pub enum StreamEvent {
+    /// Position in the queue of the model while waiting for it.
+    Queued(usize),
+    Token(String),
+    Done(Generation),
+    Error(LlmError),
+}
+
+impl StreamEvent {
+    pub fn into_sse(self) -> Result<Event, serde_json::Error> {
+        match self {
+            StreamEvent::Queued(position) => Event::default()
+                .event("queued")
+                .json_data(serde_json::json!({ "position": position })),
+            StreamEvent::Token(text) => Event::default()
+                .event("token")
+                .json_data(serde_json::json!({ "text": text })),
+            StreamEvent::Done(generation) => {
+                let mut done = serde_json::json!({
+                    "usage": generation.usage,
+                    "finish_reason": generation.finish_reason,
+                });
+                if let Some(truncated) = generation.truncated {
+                    done["truncated"] = serde_json::json!(truncated);
+                }
+                Event::default().event("done").json_data(done)
+            }
+            StreamEvent::Error(e) => Event::default()
+                .event("error")
+                .json_data(serde_json::json!({ "error": e.to_string(), "code": e.code() })),
+        }
+    }
+}
+
+
+// OpenAI compatible chat completions, see https://platform.openai.com/docs/api-reference/chat
+
+// POST /v1/chat/completions
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChatCompletionRequest {
+    pub model: Option<String>,
+    pub messages: Vec<ChatMessage>,
+    /// temperature, top_p, max_tokens, stop and seed as in OpenAI, plus top_k, repeat_penalty,
+    /// repeat_last_n and stop_token_ids
+    #[serde(flatten)]
+    pub params: GenerationParams,
+    pub stream: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CompletionUsage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+}
+
+impl From<&Usage> for CompletionUsage {
+    fn from(usage: &Usage) -> Self {
+        CompletionUsage {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            total_tokens: usage.prompt_tokens + usage.completion_tokens,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatChoice {
+    pub index: usize,
+    pub message: ChatMessage,
+    pub finish_reason: FinishReason,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatCompletionResponse {
+    pub id: String,
+    pub object: &'static str,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<ChatChoice>,
+    pub usage: CompletionUsage,
+}
+
+/// The `delta` of a streamed chunk only carries the fields that changed.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChatDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatChunkChoice {
+    pub index: usize,
+    pub delta: ChatDelta,
+    pub finish_reason: Option<FinishReason>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatCompletionChunk {
+    pub id: String,
+    pub object: &'static str,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<ChatChunkChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<CompletionUsage>,
+}
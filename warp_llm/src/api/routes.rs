@@ -0,0 +1,214 @@
+use std::convert::Infallible;
+use warp::Filter;
+
+use super::handlers;
+use crate::llm::auth::Caller;
+use super::state::AppState;
+
+
+// A function to build our routes
+pub fn routes(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    generate(state.clone())
+    .or(cancel_generation(state.clone()))
+    .or(chat(state.clone()))
+    .or(chat_completions(state.clone()))
+    .or(list_models(state.clone()))
+    .or(model_info(state.clone()))
+    .or(model_status(state.clone()))
+    .or(swap_model(state.clone()))
+    .or(create_session(state.clone()))
+    .or(get_session(state.clone()))
+    .or(delete_session(state.clone()))
+    .or(session_message(state.clone()))
+    .or(fork_session(state.clone()))
+    .or(usage(state.clone()))
+    .or(all_usage(state.clone()))
+    .or(metrics(state.clone()))
+    .or(healthz())
+    .or(readyz(state))
+}
+
+fn with_state(state: AppState) -> impl Filter<Extract = (AppState,), Error = Infallible> + Clone {
+    warp::any().map(move || state.clone()) // Clone Arc for each request
+}
+
+// The key in `Authorization: Bearer <key>`; requests without a valid one are rejected
+// with 401 when the server runs with --api-keys
+fn with_caller(state: AppState) -> impl Filter<Extract = (Caller,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::addr::remote())
+        .and(with_state(state))
+        .and_then(handlers::authenticate)
+}
+
+// POST /generate  {"prompt":"Who are you?","temperature":0}
+fn generate(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("generate")
+        .and(warp::post())
+        .and(with_caller(state.clone()))
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(warp::header::optional::<String>("accept"))
+        .and(with_state(state))
+        .and_then(handlers::generate)
+}
+
+// POST /generate/{id}/cancel
+fn cancel_generation(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("generate" / String / "cancel")
+        .and(warp::post())
+        .and(with_caller(state.clone()))
+        .and(with_state(state))
+        .and_then(handlers::cancel_generation)
+}
+
+// POST /chat  {"messages":[{"role":"user","content":"Who are you?"}]}
+fn chat(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("chat")
+        .and(warp::post())
+        .and(with_caller(state.clone()))
+        .and(warp::body::content_length_limit(1024 * 64))
+        .and(warp::body::json())
+        .and(warp::header::optional::<String>("accept"))
+        .and(with_state(state))
+        .and_then(handlers::chat)
+}
+
+// POST /v1/chat/completions  {"model":"0.5b","messages":[{"role":"user","content":"Who are you?"}]}
+fn chat_completions(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("v1" / "chat" / "completions")
+        .and(warp::post())
+        .and(with_caller(state.clone()))
+        .and(warp::body::content_length_limit(1024 * 64))
+        .and(warp::body::json())
+        .and(with_state(state))
+        .and_then(handlers::chat_completions)
+}
+
+// GET /v1/models
+fn list_models(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("v1" / "models")
+        .and(warp::get())
+        .and(with_caller(state.clone()))
+        .and(with_state(state))
+        .and_then(handlers::list_models)
+}
+
+// GET /v1/models/{id}
+fn model_info(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("v1" / "models" / String)
+        .and(warp::get())
+        .and(with_caller(state.clone()))
+        .and(with_state(state))
+        .and_then(handlers::model_info)
+}
+
+// GET /admin/models/{id}
+fn model_status(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "models" / String)
+        .and(warp::get())
+        .and(with_caller(state.clone()))
+        .and(with_state(state))
+        .and_then(handlers::model_status)
+}
+
+// POST /admin/models/{id}/swap  {"gguf":"/models/qwen2.5-coder-14b-instruct-q4_0.gguf"}
+fn swap_model(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "models" / String / "swap")
+        .and(warp::post())
+        .and(with_caller(state.clone()))
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(with_state(state))
+        .and_then(handlers::swap_model)
+}
+
+// POST /sessions  {"model":"0.5b","system":"Answer briefly."}
+fn create_session(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("sessions")
+        .and(warp::post())
+        .and(with_caller(state.clone()))
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(with_state(state))
+        .and_then(handlers::create_session)
+}
+
+// GET /sessions/{id}
+fn get_session(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("sessions" / String)
+        .and(warp::get())
+        .and(with_caller(state.clone()))
+        .and(with_state(state))
+        .and_then(handlers::get_session)
+}
+
+// DELETE /sessions/{id}
+fn delete_session(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("sessions" / String)
+        .and(warp::delete())
+        .and(with_caller(state.clone()))
+        .and(with_state(state))
+        .and_then(handlers::delete_session)
+}
+
+// POST /sessions/{id}/messages  {"content":"Who are you?"}
+fn session_message(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("sessions" / String / "messages")
+        .and(warp::post())
+        .and(with_caller(state.clone()))
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(warp::header::optional::<String>("accept"))
+        .and(with_state(state))
+        .and_then(handlers::session_message)
+}
+
+// POST /sessions/{id}/fork
+fn fork_session(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("sessions" / String / "fork")
+        .and(warp::post())
+        .and(with_caller(state.clone()))
+        .and(with_state(state))
+        .and_then(handlers::fork_session)
+}
+
+// GET /v1/usage  (limits and usage of the caller's key)
+fn usage(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("v1" / "usage")
+        .and(warp::get())
+        .and(with_caller(state))
+        .and_then(handlers::usage)
+}
+
+// GET /admin/usage  (all keys)
+fn all_usage(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("admin" / "usage")
+        .and(warp::get())
+        .and(with_caller(state.clone()))
+        .and(with_state(state))
+        .and_then(handlers::all_usage)
+}
+
+// GET /metrics  (Prometheus text format)
+fn metrics(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("metrics")
+        .and(warp::get())
+        .and(with_state(state))
+        .and_then(handlers::metrics)
+}
+
+// GET /healthz
+fn healthz() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("healthz")
+        .and(warp::get())
+        .and_then(handlers::healthz)
+}
+
+// GET /readyz
+fn readyz(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("readyz")
+        .and(warp::get())
+        .and(with_state(state))
+        .and_then(handlers::readyz)
+}
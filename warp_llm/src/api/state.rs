@@ -0,0 +1,26 @@
+use std::sync::Arc; // Use Arc for thread-safe reference counting
+use std::sync::atomic::AtomicBool;
+
+use crate::llm::auth::ApiKeys;
+use crate::llm::cancel::InFlight;
+use crate::llm::llm::Args;
+use crate::llm::pool::ModelPool;
+use crate::llm::sessions::SessionStore;
+
+// only held for bookkeeping, never across a generation, so a std Mutex is enough
+pub type SharedSessions = Arc<std::sync::Mutex<SessionStore>>;
+
+// Everything a handler needs, cloned (cheaply, via Arc) for each request
+#[derive(Clone)]
+pub struct AppState {
+    /// Each model runs on an engine thread of its own; handlers submit generations to it.
+    pub models: Arc<ModelPool>,
+    pub args: Arc<Args>,
+    pub sessions: SharedSessions,
+    /// Running `/generate` requests, so they can be cancelled by id.
+    pub in_flight: Arc<InFlight>,
+    /// Set once the default model is loaded and warmed up.
+    pub ready: Arc<AtomicBool>,
+    /// The keys of `--api-keys`; without them the API is open to anybody.
+    pub keys: Option<Arc<ApiKeys>>,
+}
@@ -0,0 +1,327 @@
+// API keys and their rate limits, read from the file given with `--api-keys`
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_derive::{Deserialize, Serialize};
+
+use super::llm_ops::Usage;
+
+
+/// One key of the keys file, e.g.
+///
+/// ```toml
+/// [[keys]]
+/// name = "team-a"
+/// key = "sk-team-a-5f0c..."
+/// tokens_per_minute = 20000
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyEntry {
+    name: String,
+    key: String,
+    requests_per_minute: Option<u32>,
+    tokens_per_minute: Option<u32>,
+    /// May use the `/admin` endpoints.
+    #[serde(default)]
+    admin: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeysFile {
+    /// Limits of the keys that set none of their own; no limit when left out.
+    requests_per_minute: Option<u32>,
+    tokens_per_minute: Option<u32>,
+    keys: Vec<KeyEntry>,
+}
+
+/// Holds up to `per_minute` units and refills at `per_minute` units a minute. Tokens are only
+/// known once a generation is over, so they are charged afterwards and may overdraw the bucket;
+/// the key then waits until the debt is paid off.
+#[derive(Debug)]
+struct TokenBucket {
+    per_minute: f64,
+    level: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(per_minute: u32) -> Self {
+        TokenBucket { per_minute: per_minute as f64, level: per_minute as f64, updated: Instant::now() }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let refilled = now.duration_since(self.updated).as_secs_f64() * self.per_minute / 60.0;
+        self.level = (self.level + refilled).min(self.per_minute);
+        self.updated = now;
+    }
+
+    /// How long until one unit is available, zero when it is.
+    fn wait(&self) -> Duration {
+        if self.level >= 1.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((1.0 - self.level) * 60.0 / self.per_minute)
+    }
+}
+
+/// What a key used since the server started.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct KeyUsage {
+    /// Generations admitted.
+    pub requests: u64,
+    /// Generations turned away with 429.
+    pub rate_limited: u64,
+    pub prompt_tokens: u64,
+    pub cached_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+#[derive(Debug)]
+struct KeyState {
+    requests: Option<TokenBucket>,
+    tokens: Option<TokenBucket>,
+    usage: KeyUsage,
+}
+
+#[derive(Debug)]
+pub struct ApiKey {
+    pub name: String,
+    pub admin: bool,
+    requests_per_minute: Option<u32>,
+    tokens_per_minute: Option<u32>,
+    state: Mutex<KeyState>,
+}
+
+/// A key's limits, what is left of them right now and its usage, as served by `/v1/usage`.
+#[derive(Debug, Serialize)]
+pub struct KeyReport {
+    pub name: String,
+    pub admin: bool,
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_minute: Option<u32>,
+    /// Negative while the key pays off tokens it overdrew.
+    pub remaining_requests: Option<i64>,
+    pub remaining_tokens: Option<i64>,
+    pub usage: KeyUsage,
+}
+
+impl ApiKey {
+    pub fn report(&self) -> KeyReport {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let remaining = |bucket: &mut Option<TokenBucket>| {
+            bucket.as_mut().map(|b| {
+                b.refill(now);
+                b.level.floor() as i64
+            })
+        };
+        KeyReport {
+            name: self.name.clone(),
+            admin: self.admin,
+            requests_per_minute: self.requests_per_minute,
+            tokens_per_minute: self.tokens_per_minute,
+            remaining_requests: remaining(&mut state.requests),
+            remaining_tokens: remaining(&mut state.tokens),
+            usage: state.usage.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    /// No `Authorization: Bearer <key>` header.
+    Missing,
+    /// A key that is not in the keys file.
+    Invalid,
+    /// The key may not use this endpoint.
+    Forbidden,
+    /// An `/admin` endpoint called from another host while the server runs without keys.
+    RemoteAdmin,
+    /// The key used up its `limit` (`requests_per_minute` or `tokens_per_minute`).
+    RateLimited { limit: &'static str, retry_after: Duration },
+}
+
+impl AuthError {
+    /// Machine readable identifier sent to clients as `code`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AuthError::Missing => "missing_api_key",
+            AuthError::Invalid => "invalid_api_key",
+            AuthError::Forbidden | AuthError::RemoteAdmin => "forbidden",
+            AuthError::RateLimited { .. } => "rate_limited",
+        }
+    }
+
+    /// Whole seconds until a rate limited key may try again.
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            AuthError::RateLimited { retry_after, .. } => Some(retry_after.as_secs_f64().ceil() as u64),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "missing API key, send it as `Authorization: Bearer <key>`"),
+            AuthError::Invalid => write!(f, "invalid API key"),
+            AuthError::Forbidden => write!(f, "this API key may not use this endpoint"),
+            AuthError::RemoteAdmin => {
+                write!(f, "without --api-keys the /admin endpoints only answer requests from this host")
+            }
+            AuthError::RateLimited { limit, retry_after } => {
+                write!(f, "{} of this API key exceeded, retry in {:.1}s", limit, retry_after.as_secs_f64())
+            }
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+#[derive(Debug)]
+pub struct ApiKeys {
+    // by the secret key
+    keys: HashMap<String, Arc<ApiKey>>,
+}
+
+impl ApiKeys {
+    /// Reads a `.toml` or `.json` keys file.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let load_error = |e: &dyn fmt::Display| anyhow::anyhow!("{}: {}", path.display(), e);
+        let text = std::fs::read_to_string(path).map_err(|e| load_error(&e))?;
+        let file: KeysFile = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str(&text).map_err(|e| load_error(&e))?,
+            _ => toml::from_str(&text).map_err(|e| load_error(&e))?,
+        };
+        let mut keys = HashMap::new();
+        let mut names = HashSet::new();
+        for entry in file.keys {
+            // the name stands for the key, e.g. as the owner of its sessions
+            if !names.insert(entry.name.clone()) {
+                return Err(load_error(&format!("the name `{}` is used by more than one key", entry.name)));
+            }
+            let requests_per_minute = entry.requests_per_minute.or(file.requests_per_minute);
+            let tokens_per_minute = entry.tokens_per_minute.or(file.tokens_per_minute);
+            if requests_per_minute == Some(0) || tokens_per_minute == Some(0) {
+                return Err(load_error(&format!("the limits of key `{}` must be positive", entry.name)));
+            }
+            let key = ApiKey {
+                state: Mutex::new(KeyState {
+                    requests: requests_per_minute.map(TokenBucket::new),
+                    tokens: tokens_per_minute.map(TokenBucket::new),
+                    usage: KeyUsage::default(),
+                }),
+                name: entry.name,
+                admin: entry.admin,
+                requests_per_minute,
+                tokens_per_minute,
+            };
+            if keys.insert(entry.key, Arc::new(key)).is_some() {
+                return Err(load_error(&"the same key is listed twice"));
+            }
+        }
+        Ok(ApiKeys { keys })
+    }
+
+    /// The caller sending `authorization`, the value of the `Authorization` header.
+    pub fn authenticate(&self, authorization: Option<&str>) -> Result<Caller, AuthError> {
+        let key = authorization.and_then(|a| a.strip_prefix("Bearer ")).ok_or(AuthError::Missing)?;
+        let key = self.keys.get(key.trim()).ok_or(AuthError::Invalid)?;
+        Ok(Caller { key: Some(key.clone()), local: false })
+    }
+
+    /// All keys, by name.
+    pub fn reports(&self) -> Vec<KeyReport> {
+        let mut reports: Vec<_> = self.keys.values().map(|key| key.report()).collect();
+        reports.sort_by(|a, b| a.name.cmp(&b.name));
+        reports
+    }
+}
+
+/// Who sent a request: one of the keys, or anybody when the server runs without `--api-keys`.
+#[derive(Debug, Clone, Default)]
+pub struct Caller {
+    key: Option<Arc<ApiKey>>,
+    // connected over loopback
+    local: bool,
+}
+
+impl Caller {
+    /// Anybody, when the server runs without keys; `local` when connected over loopback.
+    pub fn anonymous(local: bool) -> Self {
+        Caller { key: None, local }
+    }
+
+    pub fn key(&self) -> Option<&ApiKey> {
+        self.key.as_deref()
+    }
+
+    /// The name of the key, which owns the sessions and requests the caller starts.
+    pub fn owner(&self) -> Option<&str> {
+        self.key.as_deref().map(|key| key.name.as_str())
+    }
+
+    /// Admin keys, or without keys callers on this host only: a swap loads any file the server
+    /// can read.
+    pub fn require_admin(&self) -> Result<(), AuthError> {
+        match &self.key {
+            Some(key) if !key.admin => Err(AuthError::Forbidden),
+            Some(_) => Ok(()),
+            None if self.local => Ok(()),
+            None => Err(AuthError::RemoteAdmin),
+        }
+    }
+
+    /// Takes one request of the key's budget for a generation, or tells how long to wait when
+    /// its requests or its tokens are used up.
+    pub fn admit(&self) -> Result<(), AuthError> {
+        let Some(key) = &self.key else {
+            return Ok(());
+        };
+        let mut state = key.state.lock().unwrap();
+        let now = Instant::now();
+        let wait = |bucket: &mut Option<TokenBucket>| {
+            bucket.as_mut().map_or(Duration::ZERO, |b| {
+                b.refill(now);
+                b.wait()
+            })
+        };
+        let requests_wait = wait(&mut state.requests);
+        let tokens_wait = wait(&mut state.tokens);
+        let (limit, retry_after) = match requests_wait >= tokens_wait {
+            true => ("requests_per_minute", requests_wait),
+            false => ("tokens_per_minute", tokens_wait),
+        };
+        if !retry_after.is_zero() {
+            state.usage.rate_limited += 1;
+            return Err(AuthError::RateLimited { limit, retry_after });
+        }
+        if let Some(bucket) = &mut state.requests {
+            bucket.level -= 1.0;
+        }
+        state.usage.requests += 1;
+        Ok(())
+    }
+
+    /// Charges the prompt and completion tokens of a finished generation to the key.
+    pub fn charge(&self, usage: &Usage) {
+        let Some(key) = &self.key else {
+            return;
+        };
+        let mut state = key.state.lock().unwrap();
+        if let Some(bucket) = &mut state.tokens {
+            bucket.refill(Instant::now());
+            bucket.level -= (usage.prompt_tokens + usage.completion_tokens) as f64;
+        }
+        state.usage.prompt_tokens += usage.prompt_tokens as u64;
+        state.usage.cached_tokens += usage.cached_tokens as u64;
+        state.usage.completion_tokens += usage.completion_tokens as u64;
+    }
+}
@@ -0,0 +1,96 @@
+// Cooperative cancellation: the engine checks a request's token between decode steps
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::llm::random_id;
+
+
+/// Shared flag telling the engine to stop working on a request.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Cancels its token when dropped, e.g. along with the handler of a client that disconnected
+/// while the request runs on a task of its own.
+pub struct CancelOnDrop(pub CancelToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// The running requests that can be cancelled by id.
+#[derive(Default)]
+pub struct InFlight {
+    // with the name of the key that started the request, `None` without keys
+    tokens: Mutex<HashMap<String, (Option<String>, CancelToken)>>,
+}
+
+impl InFlight {
+    /// Registers a new request of `owner` under a fresh id.
+    pub fn start(self: &Arc<Self>, owner: Option<&str>) -> Ticket {
+        let ticket = Ticket { id: random_id("gen-"), token: CancelToken::default(), in_flight: self.clone() };
+        let entry = (owner.map(str::to_string), ticket.token.clone());
+        self.tokens.lock().unwrap().insert(ticket.id.clone(), entry);
+        ticket
+    }
+
+    /// Cancels request `id` of `owner`; `false` when no such request of theirs is running.
+    pub fn cancel(&self, id: &str, owner: Option<&str>) -> bool {
+        match self.tokens.lock().unwrap().get(id) {
+            Some((started_by, token)) if started_by.as_deref() == owner => {
+                token.cancel();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A registered request. Dropping it, e.g. because warp dropped the handler of a client that
+/// disconnected, cancels the request and forgets its id.
+pub struct Ticket {
+    pub id: String,
+    pub token: CancelToken,
+    in_flight: Arc<InFlight>,
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.token.cancel();
+        self.in_flight.tokens.lock().unwrap().remove(&self.id);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_owner_cancels_a_request() {
+        let in_flight = Arc::new(InFlight::default());
+        let ticket = in_flight.start(Some("ka"));
+        assert!(!in_flight.cancel(&ticket.id, Some("kb")));
+        assert!(!in_flight.cancel(&ticket.id, None));
+        assert!(!ticket.token.is_cancelled());
+        assert!(in_flight.cancel(&ticket.id, Some("ka")));
+        assert!(ticket.token.is_cancelled());
+
+        let id = ticket.id.clone();
+        drop(ticket);
+        assert!(!in_flight.cancel(&id, Some("ka")));
+    }
+}
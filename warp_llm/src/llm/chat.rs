@@ -0,0 +1,119 @@
+// Conversation types and the chat templates of the supported model families
+
+use serde_derive::{Deserialize, Serialize};
+
+use super::llm::Which;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+/// One entry of a chat conversation, e.g. `{"role": "user", "content": "hi"}`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: Role, content: impl Into<String>) -> Self {
+        ChatMessage { role, content: content.into() }
+    }
+}
+
+/// Checks the shape of a conversation: at most one system message and only in first position,
+/// ending with a user message for the assistant to answer.
+pub fn validate(messages: &[ChatMessage]) -> Result<(), String> {
+    let Some(last) = messages.last() else {
+        return Err("`messages` must contain at least one message".to_string());
+    };
+    if let Some(i) = messages.iter().skip(1).position(|m| m.role == Role::System) {
+        return Err(format!("only the first message may have role `system`, found one at index {}", i + 1));
+    }
+    if last.role != Role::User {
+        return Err("the last message must have role `user`".to_string());
+    }
+    Ok(())
+}
+
+
+/// Prompt format the model was fine-tuned on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatTemplate {
+    /// `<|im_start|>role\n...<|im_end|>` used by the Qwen2 / Qwen2.5 instruct models
+    #[default]
+    ChatMl,
+    /// `<｜User｜>...<｜Assistant｜>` used by the DeepSeek-R1 distills
+    DeepSeek,
+}
+
+impl ChatTemplate {
+    pub fn for_model(which: Which) -> Self {
+        match which {
+            Which::DeepseekR1Qwen7B => ChatTemplate::DeepSeek,
+            _ => ChatTemplate::ChatMl,
+        }
+    }
+
+    /// The token the model emits at the end of its turn.
+    pub fn eos_token(&self) -> &'static str {
+        match self {
+            ChatTemplate::ChatMl => "<|im_end|>",
+            ChatTemplate::DeepSeek => "<｜end▁of▁sentence｜>",
+        }
+    }
+
+    /// Renders the conversation and opens the assistant turn. `default_system` is used
+    /// when the conversation does not start with its own system message.
+    pub fn render(&self, messages: &[ChatMessage], default_system: Option<&str>) -> String {
+        let system = match messages.first() {
+            Some(m) if m.role == Role::System => Some(m.content.as_str()),
+            _ => default_system,
+        };
+        let turns = messages.iter().filter(|m| m.role != Role::System);
+
+        let mut prompt_str = String::new();
+        match self {
+            ChatTemplate::ChatMl => {
+                if let Some(system) = system {
+                    prompt_str += &format!("<|im_start|>system\n{system}<|im_end|>\n");
+                }
+                for message in turns {
+                    let role = match message.role {
+                        Role::User => "user",
+                        _ => "assistant",
+                    };
+                    prompt_str += &format!("<|im_start|>{role}\n{}<|im_end|>\n", message.content);
+                }
+                prompt_str += "<|im_start|>assistant\n";
+            }
+            ChatTemplate::DeepSeek => {
+                // the system prompt goes in front without any marker
+                if let Some(system) = system {
+                    prompt_str += system;
+                }
+                for message in turns {
+                    match message.role {
+                        Role::User => prompt_str += &format!("<｜User｜>{}", message.content),
+                        _ => {
+                            // like the official template, earlier reasoning is not fed back to the model
+                            let content = match message.content.rsplit_once("</think>") {
+                                Some((_, answer)) => answer.trim_start(),
+                                None => message.content.as_str(),
+                            };
+                            prompt_str += &format!("<｜Assistant｜>{content}<｜end▁of▁sentence｜>");
+                        }
+                    }
+                }
+                prompt_str += "<｜Assistant｜>";
+            }
+        }
+        prompt_str
+    }
+}
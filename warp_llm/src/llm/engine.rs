@@ -0,0 +1,697 @@
+// Continuous batching: one thread owns the model and advances every active sequence with a
+// single batched `forward` per decode step, admitting new requests between steps and running
+// their prompts a chunk per step.
+
+use std::collections::VecDeque;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, mpsc};
+use std::time::{Duration, Instant};
+
+use candle_core::{Device, Tensor};
+use candle_transformers::generation::LogitsProcessor;
+use tokenizers::Tokenizer;
+use tokio::sync::oneshot;
+
+use super::cancel::CancelToken;
+use super::error::LlmError;
+use super::kv_cache::{BlockAllocator, BlockTable};
+use super::llm::Args;
+use super::llm_ops::{FinishReason, Generation, Qwen2, TokenOutputStream, Truncated, Usage};
+use super::metrics::METRICS;
+use super::params::{GenerationConfig, Truncation};
+use super::prefix_cache::PrefixCache;
+use super::registry::ModelEntry;
+use super::sessions::KvSnapshot;
+use super::stop::StopMatcher;
+
+
+/// Receives every decoded fragment (stop strings already cut out); returning `false` stops the generation.
+pub type OnToken = Box<dyn FnMut(&str) -> bool + Send>;
+
+/// Gets the tokens a generation used once it is over, however it ended: also when it failed
+/// or its client went away.
+pub type OnUsage = Box<dyn FnOnce(&Usage) + Send>;
+
+/// Gets the 1-based queue position of a waiting request after every engine step; returning
+/// `false` takes the request off the queue.
+pub type OnQueued = Box<dyn FnMut(usize) -> bool + Send>;
+
+/// A generation submitted to the engine.
+pub struct GenerateRequest {
+    /// Names the generation in traces and logs.
+    pub request_id: String,
+    /// The prompt, already rendered with the chat template.
+    pub prompt: String,
+    pub config: GenerationConfig,
+    /// Keys and values to continue from (chat sessions); prefill skips its longest common
+    /// prefix with the prompt. Pass `KvSnapshot::default()` to start from scratch.
+    pub kv: KvSnapshot,
+    pub on_token: OnToken,
+    pub on_queued: Option<OnQueued>,
+    pub on_usage: Option<OnUsage>,
+    /// Checked between decode steps; a cancelled request ends with what it generated so far.
+    pub cancel: CancelToken,
+}
+
+/// The result of a generation and the kv cache it leaves behind.
+pub type GenerateResult = Result<(Generation, KvSnapshot), LlmError>;
+
+struct Job {
+    request: GenerateRequest,
+    done: oneshot::Sender<GenerateResult>,
+    queued_at: Instant,
+    deadline: Option<Instant>,
+    // the span of the HTTP request, so the engine's spans show up under it
+    parent: tracing::Span,
+}
+
+/// Cheap to clone handle used by the HTTP handlers to talk to the engine thread.
+#[derive(Clone)]
+pub struct EngineHandle {
+    jobs: mpsc::Sender<Job>,
+    model: Arc<ModelEntry>,
+    tokenizer: Tokenizer,
+    context_length: usize,
+    // requests sent to the engine and not started yet
+    queued: Arc<AtomicUsize>,
+    max_queue: usize,
+}
+
+impl EngineHandle {
+    /// The registry entry of the model running on this engine.
+    pub fn model(&self) -> &ModelEntry {
+        &self.model
+    }
+
+    /// Positions available to prompt and generated tokens together.
+    pub fn context_length(&self) -> usize {
+        self.context_length
+    }
+
+    /// The number of tokens `text` is encoded to, as the engine will count them.
+    pub fn count_tokens(&self, text: &str) -> Result<usize, LlmError> {
+        let encoding = self.tokenizer.encode(text, true).map_err(|e| LlmError::Tokenizer(e.to_string()))?;
+        Ok(encoding.len())
+    }
+
+    /// The number of requests waiting for this engine.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Queues a generation and waits until it finishes; `on_token` runs on the engine thread.
+    /// Fails right away when `--max-queue` requests are already waiting. Dropping the returned
+    /// future while the request waits takes it off the queue.
+    pub async fn generate(&self, request: GenerateRequest) -> GenerateResult {
+        if self.queued.fetch_add(1, Ordering::Relaxed) >= self.max_queue {
+            let depth = self.queued.fetch_sub(1, Ordering::Relaxed) - 1;
+            return Err(LlmError::QueueFull { depth });
+        }
+        let (done, result) = oneshot::channel();
+        let queued_at = Instant::now();
+        // no deadline for a limit too far out to represent, e.g. a huge --request-timeout-secs
+        let deadline = request.config.timeout.and_then(|timeout| queued_at.checked_add(timeout));
+        let job = Job { request, done, queued_at, deadline, parent: tracing::Span::current() };
+        if self.jobs.send(job).is_err() {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            return Err(LlmError::Unavailable("the inference engine has stopped".to_string()));
+        }
+        result
+            .await
+            .map_err(|_| LlmError::Unavailable("the inference engine dropped the request".to_string()))?
+    }
+}
+
+/// Moves the model onto its own thread and returns the handle to submit work to it.
+pub fn spawn(mut model: Qwen2, tokenizer: Tokenizer, entry: &ModelEntry, args: &Args) -> Result<EngineHandle, LlmError> {
+    let device = candle_examples::device(args.cpu).map_err(|e| LlmError::ModelLoad(e.to_string()))?;
+    let eos_token = entry.eos_token();
+    let eos_token = tokenizer
+        .token_to_id(eos_token)
+        .ok_or_else(|| LlmError::Tokenizer(format!("the tokenizer has no {eos_token} token")))?;
+    let (jobs, rx) = mpsc::channel();
+    let context_length = model.context_length();
+    model.set_kv_window(args.kv_window.map(NonZeroUsize::get));
+    model
+        .set_kv_cache(args.kv_cache_mb * 1024 * 1024, args.kv_cache_dtype)
+        .map_err(|e| LlmError::ModelLoad(e.to_string()))?;
+    let queued = Arc::new(AtomicUsize::new(0));
+    let handle = EngineHandle {
+        jobs,
+        model: Arc::new(entry.clone()),
+        tokenizer: tokenizer.clone(),
+        context_length,
+        queued: queued.clone(),
+        max_queue: args.max_queue,
+    };
+    static NEXT_ENGINE_ID: AtomicU64 = AtomicU64::new(1);
+    let engine = Engine {
+        id: NEXT_ENGINE_ID.fetch_add(1, Ordering::Relaxed),
+        model_id: entry.id.clone(),
+        model,
+        tokenizer,
+        device,
+        eos_token,
+        context_length,
+        prefill_chunk: match (args.split_prompt, args.prefill_chunk_size) {
+            (true, _) => 1,
+            (false, 0) => usize::MAX,
+            (false, size) => size,
+        },
+        prefix_cache: (args.prefix_cache_mb > 0).then(|| PrefixCache::new(args.prefix_cache_mb * 1024 * 1024)),
+        max_batch_size: args.max_batch_size.max(1),
+        max_queue_wait: Duration::from_secs(args.max_queue_wait_secs),
+        queued,
+        jobs: rx,
+        waiting: VecDeque::new(),
+        active: Vec::new(),
+    };
+    std::thread::Builder::new()
+        .name("llm-engine".to_string())
+        .spawn(move || engine.run())
+        .map_err(|e| LlmError::Unavailable(format!("failed to start the inference engine: {}", e)))?;
+    Ok(handle)
+}
+
+
+// Per-request generation state: its own kv cache, position, sampler and detokenizer
+struct Sequence {
+    config: GenerationConfig,
+    on_token: OnToken,
+    on_usage: Option<OnUsage>,
+    cancel: CancelToken,
+    deadline: Option<Instant>,
+    done: oneshot::Sender<GenerateResult>,
+    prompt_tokens: usize,
+    reused: usize,
+    // the prompt tokens still to run through the model, a chunk per engine step
+    pending: Vec<u32>,
+    kv: KvSnapshot,
+    tos: TokenOutputStream,
+    stop: StopMatcher,
+    logits_processor: LogitsProcessor,
+    all_tokens: Vec<u32>,
+    text: String,
+    finish_reason: Option<FinishReason>,
+    truncated: Option<Truncated>,
+    queued_at: Instant,
+    prefill_start: Instant,
+    prompt_dt: Duration,
+    decode_start: Instant,
+    // when the previous token was handed out, which the latency of the next one counts from
+    last_token: Option<Instant>,
+    request_id: String,
+    // closed when the sequence is dropped, which logs its duration
+    span: tracing::Span,
+}
+
+impl Sequence {
+    // Done with its prompt and waiting for its next token
+    fn decoding(&self) -> bool {
+        self.finish_reason.is_none() && self.pending.is_empty()
+    }
+
+    // Handles a freshly sampled token: check the stop conditions, detokenize, hand the text to the caller
+    fn push_token(&mut self, token: u32, eos_token: u32, model_id: &str) -> candle_core::Result<()> {
+        // since the previous token, so the prefill chunks run between decode steps count too
+        let now = Instant::now();
+        if let Some(last_token) = self.last_token.replace(now) {
+            let latency = now.duration_since(last_token).as_secs_f64();
+            METRICS.inter_token_latency.with_label_values(&[model_id]).observe(latency);
+        }
+        self.all_tokens.push(token);
+        let mut stop_string = false;
+        if token == eos_token {
+            self.finish_reason = Some(FinishReason::Stop);
+        } else if self.config.stop_token_ids.contains(&token) {
+            self.finish_reason = Some(FinishReason::StopSequence);
+        } else if let Some(t) = self.tos.next_token(token)? {
+            stop_string = self.emit(&t);
+        }
+        if self.finish_reason.is_none() && self.all_tokens.len() >= self.config.max_tokens {
+            self.finish_reason = Some(FinishReason::Length);
+        }
+        // a stop string drops what follows it, every other end hands out the text held back
+        if self.finish_reason.is_some() && !stop_string {
+            if let Some(rest) = self.tos.decode_rest()? {
+                self.emit(&rest);
+            }
+            // empty when the rest ran into a stop string
+            let held = self.stop.flush();
+            self.deliver(held);
+        }
+        Ok(())
+    }
+
+    // Ends the sequence between decode steps, handing out the text held back so far
+    fn end(&mut self, finish_reason: FinishReason) {
+        self.finish_reason = Some(finish_reason);
+        if let Ok(Some(rest)) = self.tos.decode_rest() {
+            let (text, _) = self.stop.push(&rest);
+            self.deliver(text);
+        }
+        let held = self.stop.flush();
+        self.deliver(held);
+    }
+
+    // Runs decoded text through the stop strings; `true` when it ran into one
+    fn emit(&mut self, fragment: &str) -> bool {
+        let (text, hit) = self.stop.push(fragment);
+        if hit {
+            self.finish_reason = Some(FinishReason::StopSequence);
+        }
+        self.deliver(text);
+        hit
+    }
+
+    fn deliver(&mut self, text: String) {
+        if text.is_empty() {
+            return;
+        }
+        self.text += &text;
+        if !(self.on_token)(&text) {
+            self.finish_reason.get_or_insert(FinishReason::Cancelled);
+        }
+    }
+
+    fn sample(&mut self, logits: &Tensor) -> candle_core::Result<u32> {
+        let logits = if self.config.repeat_penalty == 1. {
+            logits.clone()
+        } else {
+            let start_at = self.all_tokens.len().saturating_sub(self.config.repeat_last_n);
+            candle_transformers::utils::apply_repeat_penalty(
+                logits,
+                self.config.repeat_penalty,
+                &self.all_tokens[start_at..],
+            )?
+        };
+        self.logits_processor.sample(&logits)
+    }
+
+    // The tokens used so far
+    fn usage(&self) -> Usage {
+        let sampled = self.all_tokens.len().saturating_sub(1);
+        // fewer than the prompt when it ended during prefill
+        let new_tokens = self.prompt_tokens - self.reused - self.pending.len();
+        Usage {
+            prompt_tokens: self.prompt_tokens,
+            cached_tokens: self.reused,
+            completion_tokens: self.all_tokens.len(),
+            prompt_tokens_per_sec: new_tokens as f64 / self.prompt_dt.as_secs_f64(),
+            completion_tokens_per_sec: sampled as f64 / self.decode_start.elapsed().as_secs_f64(),
+        }
+    }
+
+    // Ends the sequence with an error, still reporting the tokens it used
+    fn fail(mut self, e: LlmError) {
+        if let Some(on_usage) = self.on_usage.take() {
+            on_usage(&self.usage());
+        }
+        let _ = self.done.send(Err(e));
+    }
+
+    fn finish(mut self, model_id: &str) {
+        let usage = self.usage();
+        println!(
+            "{:4} prompt tokens processed: {:.2} token/s ({} cached), {:4} tokens generated: {:.2} token/s",
+            self.prompt_tokens - self.reused - self.pending.len(),
+            usage.prompt_tokens_per_sec,
+            self.reused,
+            self.all_tokens.len().saturating_sub(1),
+            usage.completion_tokens_per_sec,
+        );
+        if let Some(on_usage) = self.on_usage.take() {
+            on_usage(&usage);
+        }
+        let labels = [model_id];
+        METRICS.prompt_tokens.with_label_values(&labels).inc_by(usage.prompt_tokens as u64);
+        METRICS.cached_tokens.with_label_values(&labels).inc_by(usage.cached_tokens as u64);
+        METRICS.completion_tokens.with_label_values(&labels).inc_by(usage.completion_tokens as u64);
+        let finish_reason = self.finish_reason.unwrap_or(FinishReason::Length);
+        METRICS.generations.with_label_values(&[model_id, finish_reason.as_str()]).inc();
+        self.span.record("prompt_tokens", usage.prompt_tokens);
+        self.span.record("completion_tokens", usage.completion_tokens);
+        self.span.record("finish_reason", finish_reason.as_str());
+        let generation = Generation {
+            text: self.text,
+            usage,
+            finish_reason,
+            truncated: self.truncated,
+        };
+        let _ = self.done.send(Ok((generation, self.kv)));
+    }
+}
+
+
+// Takes the blocks for `kv` to grow to `len` positions, evicting cached prefixes nobody else
+// holds while the pool runs short; `false` when even that does not free enough
+fn reserve_blocks(
+    allocator: &BlockAllocator,
+    prefix_cache: &mut Option<PrefixCache>,
+    kv: &mut BlockTable,
+    len: usize,
+) -> bool {
+    while !kv.reserve(len, allocator) {
+        if !prefix_cache.as_mut().is_some_and(PrefixCache::evict_unshared) {
+            return false;
+        }
+    }
+    true
+}
+
+struct Engine {
+    // unique per engine, so kv caches computed with other weights are never reused
+    id: u64,
+    // the registry id, as the metrics label
+    model_id: String,
+    model: Qwen2,
+    tokenizer: Tokenizer,
+    device: Device,
+    eos_token: u32,
+    context_length: usize,
+    // prompt tokens per forward pass of prefill
+    prefill_chunk: usize,
+    prefix_cache: Option<PrefixCache>,
+    max_batch_size: usize,
+    max_queue_wait: Duration,
+    queued: Arc<AtomicUsize>,
+    jobs: mpsc::Receiver<Job>,
+    waiting: VecDeque<Job>,
+    active: Vec<Sequence>,
+}
+
+impl Engine {
+    fn run(mut self) {
+        loop {
+            // block while there is nothing to do, otherwise only pick up what has arrived
+            if self.active.is_empty() && self.waiting.is_empty() {
+                match self.jobs.recv() {
+                    Ok(job) => self.waiting.push_back(job),
+                    Err(_) => {
+                        METRICS.queue_depth.with_label_values(&[&self.model_id]).set(0);
+                        METRICS.kv_cache_bytes.with_label_values(&[&self.model_id]).set(0);
+                        METRICS.prefix_cache_bytes.with_label_values(&[&self.model_id]).set(0);
+                        METRICS.kv_pool_used_bytes.with_label_values(&[&self.model_id]).set(0);
+                        METRICS.kv_pool_free_bytes.with_label_values(&[&self.model_id]).set(0);
+                        return;
+                    }
+                }
+            }
+            while let Ok(job) = self.jobs.try_recv() {
+                self.waiting.push_back(job);
+            }
+
+            // admit at most one sequence per step so the running ones keep decoding meanwhile;
+            // requests whose client already went away are skipped
+            while self.active.len() < self.max_batch_size
+                && let Some(job) = self.waiting.pop_front()
+            {
+                self.queued.fetch_sub(1, Ordering::Relaxed);
+                if !job.done.is_closed() {
+                    self.admit(job);
+                    break;
+                }
+            }
+            self.update_queue();
+            self.prefill_step();
+
+            // stop the sequences that were cancelled, whose client is no longer waiting, or that
+            // ran out of time
+            let now = Instant::now();
+            for seq in self.active.iter_mut().filter(|seq| seq.finish_reason.is_none()) {
+                if seq.cancel.is_cancelled() || seq.done.is_closed() {
+                    seq.end(FinishReason::Cancelled);
+                } else if seq.deadline.is_some_and(|deadline| now >= deadline) {
+                    seq.end(FinishReason::Timeout);
+                }
+            }
+
+            // every sequence needs a block for its next position once its last one is full; the
+            // ones the pool has no block left for end here
+            let allocator = self.model.block_allocator().clone();
+            for seq in self.active.iter_mut().filter(|seq| seq.decoding()) {
+                if !reserve_blocks(&allocator, &mut self.prefix_cache, &mut seq.kv.blocks, seq.kv.tokens.len() + 1) {
+                    seq.truncated.get_or_insert_default().max_tokens = Some(seq.all_tokens.len());
+                    seq.end(FinishReason::Length);
+                }
+            }
+
+            if let Err(e) = self.model.grow_kv_cache().and_then(|()| self.decode_step()) {
+                eprintln!("Error running model: {}", e);
+                for seq in self.active.drain(..) {
+                    seq.fail(LlmError::Inference(e.to_string()));
+                }
+            }
+            self.retire_finished();
+            let kv_bytes: usize = self.active.iter().map(|seq| seq.kv.size_in_bytes()).sum();
+            METRICS.kv_cache_bytes.with_label_values(&[&self.model_id]).set(kv_bytes as i64);
+            let used = allocator.used() * allocator.block_bytes();
+            METRICS.kv_pool_used_bytes.with_label_values(&[&self.model_id]).set(used as i64);
+            let free = allocator.free() * allocator.block_bytes();
+            METRICS.kv_pool_free_bytes.with_label_values(&[&self.model_id]).set(free as i64);
+        }
+    }
+
+    // Drops the waiting jobs that waited too long or whose client went away, and tells the
+    // others their position
+    fn update_queue(&mut self) {
+        for mut job in std::mem::take(&mut self.waiting) {
+            let position = self.waiting.len() + 1;
+            let cancelled = match &mut job.request.on_queued {
+                Some(on_queued) => !on_queued(position),
+                None => false,
+            };
+            let finish_reason = if cancelled || job.request.cancel.is_cancelled() || job.done.is_closed() {
+                Some(FinishReason::Cancelled)
+            } else if job.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                Some(FinishReason::Timeout)
+            } else {
+                None
+            };
+            if let Some(finish_reason) = finish_reason {
+                // ends without having started, so the kv cache goes back untouched
+                self.queued.fetch_sub(1, Ordering::Relaxed);
+                METRICS.generations.with_label_values(&[&self.model_id, finish_reason.as_str()]).inc();
+                let generation = Generation { text: String::new(), usage: Usage::default(), finish_reason, truncated: None };
+                let _ = job.done.send(Ok((generation, job.request.kv)));
+                continue;
+            }
+            let waited = job.queued_at.elapsed();
+            if waited > self.max_queue_wait {
+                self.queued.fetch_sub(1, Ordering::Relaxed);
+                let _ = job.done.send(Err(LlmError::QueueTimeout { waited_secs: waited.as_secs() }));
+                continue;
+            }
+            self.waiting.push_back(job);
+        }
+        METRICS.queue_depth.with_label_values(&[&self.model_id]).set(self.queued.load(Ordering::Relaxed) as i64);
+    }
+
+    fn admit(&mut self, job: Job) {
+        let Job { request, done, queued_at, deadline, parent } = job;
+        let span = tracing::info_span!(
+            parent: &parent,
+            "generation",
+            request_id = %request.request_id,
+            model = %self.model_id,
+            prompt_tokens = tracing::field::Empty,
+            completion_tokens = tracing::field::Empty,
+            finish_reason = tracing::field::Empty,
+        );
+        METRICS.queue_wait.with_label_values(&[&self.model_id]).observe(queued_at.elapsed().as_secs_f64());
+        let mut seq = Sequence {
+            logits_processor: request.config.logits_processor(),
+            stop: StopMatcher::new(request.config.stop.clone()),
+            config: request.config,
+            on_token: request.on_token,
+            on_usage: request.on_usage,
+            cancel: request.cancel,
+            deadline,
+            done,
+            prompt_tokens: 0,
+            reused: 0,
+            pending: Vec::new(),
+            kv: request.kv,
+            tos: TokenOutputStream::new(self.tokenizer.clone()),
+            all_tokens: vec![],
+            text: String::new(),
+            finish_reason: None,
+            truncated: None,
+            queued_at,
+            prefill_start: Instant::now(),
+            prompt_dt: Duration::ZERO,
+            decode_start: Instant::now(),
+            last_token: None,
+            request_id: request.request_id,
+            span,
+        };
+        match seq.span.clone().in_scope(|| self.start_prefill(&mut seq, &request.prompt)) {
+            Ok(()) => self.active.push(seq),
+            Err(e) => {
+                eprintln!("{}", e);
+                seq.fail(e);
+            }
+        }
+    }
+
+    // Finds the cached prefix of the prompt and takes the blocks for the rest, which
+    // `prefill_step` then runs through the model
+    fn start_prefill(&mut self, seq: &mut Sequence, prompt: &str) -> Result<(), LlmError> {
+        let tokens = self.tokenizer.encode(prompt, true).map_err(|e| LlmError::Tokenizer(e.to_string()))?;
+        let tokens = self.fit_context(seq, tokens.get_ids())?;
+        let tokens = tokens.as_slice();
+        if seq.kv.engine_id != self.id {
+            seq.kv = KvSnapshot { engine_id: self.id, ..KvSnapshot::default() };
+        }
+        // reuse the cached prefix, but always feed at least one token to get the next logits
+        let reused = tokens.iter().zip(seq.kv.tokens.iter()).take_while(|(a, b)| a == b).count();
+        let mut reused = reused.min(tokens.len().saturating_sub(1));
+        // a longer prefix that earlier requests went through beats the one brought along
+        if let Some(prefix_cache) = &mut self.prefix_cache {
+            let prompt = &tokens[..tokens.len().saturating_sub(1)];
+            let cached = prefix_cache.longest_prefix(prompt);
+            let hit = cached > reused;
+            if hit {
+                seq.kv.blocks = prefix_cache.restore(prompt);
+                reused = cached;
+                METRICS.prefix_cache_hit_tokens.with_label_values(&[&self.model_id]).inc_by(cached as u64);
+            }
+            let result = if hit { "hit" } else { "miss" };
+            METRICS.prefix_cache_lookups.with_label_values(&[&self.model_id, result]).inc();
+        }
+        // with a window the positions to go back to may be gone, then it all starts over
+        if !seq.kv.blocks.truncate(reused) {
+            seq.kv.blocks = BlockTable::default();
+            reused = 0;
+        }
+        let allocator = self.model.block_allocator().clone();
+        if !reserve_blocks(&allocator, &mut self.prefix_cache, &mut seq.kv.blocks, tokens.len()) {
+            return Err(LlmError::KvCacheFull { tokens: tokens.len() });
+        }
+        // here rather than in a forward pass, which would hold up the sequences being decoded
+        self.model.grow_kv_cache()?;
+        seq.kv.tokens = tokens[..reused].to_vec();
+        seq.pending = tokens[reused..].to_vec();
+        seq.prompt_tokens = tokens.len();
+        seq.reused = reused;
+        seq.prompt_dt = seq.prefill_start.elapsed();
+        Ok(())
+    }
+
+    // Runs the next chunk of the prompt of one sequence, so that long prompts hold up the decode
+    // steps of the others for a chunk at a time; a sequence that fails ends with the error
+    fn prefill_step(&mut self) {
+        let Some(i) = self.active.iter().position(|seq| seq.finish_reason.is_none() && !seq.pending.is_empty()) else {
+            return;
+        };
+        let span = self.active[i].span.clone();
+        let prefilled = span.in_scope(|| self.prefill_chunk(i));
+        let seq = self.active.remove(i);
+        match prefilled {
+            // to the back, so the prompts take turns and a short one is not stuck behind a long one
+            Ok(()) => self.active.push(seq),
+            Err(e) => {
+                eprintln!("{}", e);
+                seq.fail(e);
+            }
+        }
+    }
+
+    // Runs up to `prefill_chunk` pending prompt tokens of `active[i]` on top of its kv cache and
+    // samples the first token after the last chunk
+    fn prefill_chunk(&mut self, i: usize) -> Result<(), LlmError> {
+        let seq = &mut self.active[i];
+        let chunk: Vec<u32> = seq.pending.drain(..seq.pending.len().min(self.prefill_chunk)).collect();
+        let _prefill = tracing::info_span!("prefill", tokens = chunk.len()).entered();
+        let input = Tensor::new(chunk.as_slice(), &self.device)?.unsqueeze(0)?;
+        let logits = self.model.forward(&input, &mut seq.kv.blocks)?;
+        seq.kv.tokens.extend_from_slice(&chunk);
+        // right away, so that requests arriving meanwhile share the prompt so far
+        if let Some(prefix_cache) = &mut self.prefix_cache {
+            prefix_cache.insert(&seq.kv.tokens, &seq.kv.blocks);
+            METRICS.prefix_cache_bytes.with_label_values(&[&self.model_id]).set(prefix_cache.size_in_bytes() as i64);
+        }
+        seq.prompt_dt = seq.prefill_start.elapsed();
+        if !seq.pending.is_empty() {
+            return Ok(());
+        }
+
+        let next_token = seq.sample(&logits.squeeze(0)?)?;
+        seq.prompt_dt = seq.prefill_start.elapsed();
+        seq.decode_start = Instant::now();
+        let ttft = seq.queued_at.elapsed().as_secs_f64();
+        METRICS.time_to_first_token.with_label_values(&[&self.model_id]).observe(ttft);
+        Ok(seq.push_token(next_token, self.eos_token, &self.model_id)?)
+    }
+
+    // Applies the truncation strategy and lowers `max_tokens` to what is left of the window
+    fn fit_context(&self, seq: &mut Sequence, tokens: &[u32]) -> Result<Vec<u32>, LlmError> {
+        let mut truncated = Truncated::default();
+        let budget = seq.config.prompt_budget(self.context_length);
+        let tokens = if seq.config.truncation == Truncation::Left && tokens.len() > budget {
+            truncated.prompt_tokens = tokens.len() - budget;
+            &tokens[truncated.prompt_tokens..]
+        } else {
+            tokens
+        };
+        if tokens.len() >= self.context_length {
+            return Err(LlmError::ContextOverflow { tokens: tokens.len(), context_length: self.context_length });
+        }
+        let room = self.context_length - tokens.len();
+        if seq.config.max_tokens > room {
+            seq.config.max_tokens = room;
+            truncated.max_tokens = Some(room);
+        }
+        if truncated != Truncated::default() {
+            seq.truncated = Some(truncated);
+        }
+        Ok(tokens.to_vec())
+    }
+
+    // Feeds the last sampled token of every active sequence through one batched forward
+    fn decode_step(&mut self) -> candle_core::Result<()> {
+        let batch: Vec<usize> = (0..self.active.len()).filter(|&i| self.active[i].decoding()).collect();
+        if batch.is_empty() {
+            return Ok(());
+        }
+        // shared by the whole batch, so the ids tell which requests a slow step held up
+        let request_ids: Vec<&str> = batch.iter().map(|&i| self.active[i].request_id.as_str()).collect();
+        let _step = tracing::debug_span!("decode_step", batch = batch.len(), request_ids = ?request_ids).entered();
+        let tokens: Vec<u32> = batch.iter().map(|&i| *self.active[i].all_tokens.last().unwrap_or(&0)).collect();
+        let logits = {
+            let mut kvs: Vec<&mut BlockTable> = self
+                .active
+                .iter_mut()
+                .filter(|seq| seq.decoding())
+                .map(|seq| &mut seq.kv.blocks)
+                .collect();
+            self.model.forward_batch(&tokens, &mut kvs)?
+        };
+        for (row, &i) in batch.iter().enumerate() {
+            let seq = &mut self.active[i];
+            seq.kv.tokens.push(tokens[row]);
+            let next_token = seq.sample(&logits.get(row)?)?;
+            seq.push_token(next_token, self.eos_token, &self.model_id)?;
+        }
+        Ok(())
+    }
+
+    fn retire_finished(&mut self) {
+        let mut i = 0;
+        while i < self.active.len() {
+            if self.active[i].finish_reason.is_some() {
+                let seq = self.active.swap_remove(i);
+                // the generated tokens too, for the next turn of a conversation that resends them
+                if let Some(prefix_cache) = &mut self.prefix_cache {
+                    prefix_cache.insert(&seq.kv.tokens, &seq.kv.blocks);
+                    let bytes = prefix_cache.size_in_bytes() as i64;
+                    METRICS.prefix_cache_bytes.with_label_values(&[&self.model_id]).set(bytes);
+                }
+                seq.finish(&self.model_id);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
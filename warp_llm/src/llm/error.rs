@@ -0,0 +1,89 @@
+// Errors of loading and running the model, each with a stable code for API clients
+
+use std::fmt;
+
+
+#[derive(Debug)]
+pub enum LlmError {
+    /// The text could not be encoded, or the tokenizer lacks a token the model needs.
+    Tokenizer(String),
+    /// The weights or the tokenizer could not be loaded.
+    ModelLoad(String),
+    /// The forward pass or sampling failed.
+    Inference(String),
+    /// The prompt does not fit into the context window of the model.
+    ContextOverflow { tokens: usize, context_length: usize },
+    /// The request asked for settings that are out of range.
+    InvalidParams(String),
+    /// The inference engine is not running.
+    Unavailable(String),
+    /// No model with this id is registered.
+    UnknownModel(String),
+    /// The queue of the model is full.
+    QueueFull { depth: usize },
+    /// The request was not started within `--max-queue-wait-secs`.
+    QueueTimeout { waited_secs: u64 },
+    /// The kv cache of the model (`--kv-cache-mb`) has no room for the prompt.
+    KvCacheFull { tokens: usize },
+}
+
+impl LlmError {
+    /// Machine readable identifier sent to clients as `code`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            LlmError::Tokenizer(_) => "tokenizer_error",
+            LlmError::ModelLoad(_) => "model_load_error",
+            LlmError::Inference(_) => "inference_error",
+            LlmError::ContextOverflow { .. } => "context_length_exceeded",
+            LlmError::InvalidParams(_) => "invalid_params",
+            LlmError::Unavailable(_) => "engine_unavailable",
+            LlmError::UnknownModel(_) => "model_not_found",
+            LlmError::QueueFull { .. } => "queue_full",
+            LlmError::QueueTimeout { .. } => "queue_timeout",
+            LlmError::KvCacheFull { .. } => "kv_cache_full",
+        }
+    }
+
+    /// Seconds a client should wait before retrying, for errors caused by load.
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            LlmError::QueueFull { .. } | LlmError::QueueTimeout { .. } | LlmError::KvCacheFull { .. } => Some(1),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for LlmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LlmError::Tokenizer(message) => write!(f, "tokenizer error: {}", message),
+            LlmError::ModelLoad(message) => write!(f, "failed to load the model: {}", message),
+            LlmError::Inference(message) => write!(f, "error running model: {}", message),
+            LlmError::ContextOverflow { tokens, context_length } => write!(
+                f,
+                "the prompt has {} tokens but the context window of the model is {} tokens",
+                tokens, context_length
+            ),
+            LlmError::InvalidParams(message) => write!(f, "{}", message),
+            LlmError::Unavailable(message) => write!(f, "{}", message),
+            LlmError::UnknownModel(id) => write!(f, "model `{}` is not in the registry", id),
+            LlmError::QueueFull { depth } => {
+                write!(f, "the server is busy: {} requests are already waiting for the model", depth)
+            }
+            LlmError::QueueTimeout { waited_secs } => {
+                write!(f, "the request waited {}s for the model without being started", waited_secs)
+            }
+            LlmError::KvCacheFull { tokens } => {
+                write!(f, "the server is busy: the kv cache has no room for the {} tokens of the prompt", tokens)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LlmError {}
+
+impl From<candle_core::Error> for LlmError {
+    fn from(e: candle_core::Error) -> Self {
+        LlmError::Inference(e.to_string())
+    }
+}
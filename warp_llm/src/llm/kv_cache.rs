@@ -0,0 +1,524 @@
+// Paged kv cache: the keys and values of all sequences of a model live in fixed-size blocks of
+// one shared pool per layer, and every sequence maps its positions to blocks with a block table,
+// so memory is taken a block at a time as sequences grow instead of being reserved up front
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use candle_core::{DType, Device, Result, Tensor};
+use clap::ValueEnum;
+
+
+/// Positions per block.
+pub const BLOCK_SIZE: usize = 16;
+
+// The pools are added to in chunks of about this many bytes (all layers together), so that
+// growing them never copies what they hold
+const CHUNK_BYTES: usize = 256 * 1024 * 1024;
+
+struct FreeList {
+    free: Vec<u32>,
+    // blocks handed out at least once; the pools hold at least this many
+    created: usize,
+    max_blocks: usize,
+}
+
+/// Hands out the blocks of a model's pool. Cheap to clone; every clone manages the same blocks.
+#[derive(Clone)]
+pub struct BlockAllocator {
+    blocks: Arc<Mutex<FreeList>>,
+    block_bytes: usize,
+    chunk_blocks: usize,
+}
+
+impl BlockAllocator {
+    /// Manages up to `max_blocks` blocks of `block_bytes` bytes each (all layers together).
+    pub fn new(max_blocks: usize, block_bytes: usize) -> Self {
+        let blocks = FreeList { free: Vec::new(), created: 0, max_blocks };
+        let chunk_blocks = (CHUNK_BYTES / block_bytes.max(1)).clamp(1, max_blocks.max(1));
+        BlockAllocator { blocks: Arc::new(Mutex::new(blocks)), block_bytes, chunk_blocks }
+    }
+
+    /// A block nobody uses, or `None` when all `max_blocks` are in use.
+    pub fn allocate(&self) -> Option<Arc<KvBlock>> {
+        let mut blocks = self.blocks.lock().unwrap();
+        let id = match blocks.free.pop() {
+            Some(id) => id,
+            None if blocks.created < blocks.max_blocks => {
+                blocks.created += 1;
+                (blocks.created - 1) as u32
+            }
+            None => return None,
+        };
+        Some(Arc::new(KvBlock { id, allocator: self.clone() }))
+    }
+
+    /// The number of blocks handed out so far, which the pools have to hold.
+    pub fn created(&self) -> usize {
+        self.blocks.lock().unwrap().created
+    }
+
+    pub fn max_blocks(&self) -> usize {
+        self.blocks.lock().unwrap().max_blocks
+    }
+
+    /// The number of blocks that can still be allocated.
+    pub fn free(&self) -> usize {
+        let blocks = self.blocks.lock().unwrap();
+        blocks.free.len() + blocks.max_blocks - blocks.created
+    }
+
+    /// The number of blocks in use.
+    pub fn used(&self) -> usize {
+        let blocks = self.blocks.lock().unwrap();
+        blocks.created - blocks.free.len()
+    }
+
+    /// The memory of one block across all layers.
+    pub fn block_bytes(&self) -> usize {
+        self.block_bytes
+    }
+
+    /// The number of blocks the pools are grown by at a time.
+    pub fn chunk_blocks(&self) -> usize {
+        self.chunk_blocks
+    }
+}
+
+/// One block of the pool. Sequences, chat sessions and the prefix cache share blocks through
+/// `Arc`s; the block goes back to the pool when the last of them drops it.
+pub struct KvBlock {
+    id: u32,
+    allocator: BlockAllocator,
+}
+
+impl KvBlock {
+    /// The memory of the block across all layers.
+    pub fn size_in_bytes(&self) -> usize {
+        self.allocator.block_bytes
+    }
+}
+
+impl Drop for KvBlock {
+    fn drop(&mut self) {
+        self.allocator.blocks.lock().unwrap().free.push(self.id);
+    }
+}
+
+impl std::fmt::Debug for KvBlock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "KvBlock({})", self.id)
+    }
+}
+
+/// The blocks holding the positions of one sequence, block `i` holding positions
+/// `i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE`.
+///
+/// Cloning forks the sequence: both tables share the blocks, and the first one to write into a
+/// shared block copies it (copy-on-write), so neither sees the other's later positions.
+#[derive(Debug, Clone, Default)]
+pub struct BlockTable {
+    // `None` for the blocks that fell out of the kv window
+    blocks: Vec<Option<Arc<KvBlock>>>,
+    len: usize,
+    // blocks copied on write whose contents still have to be copied by the next forward pass,
+    // (from, to)
+    copies: Vec<(Arc<KvBlock>, Arc<KvBlock>)>,
+}
+
+/// Where one forward pass of a sequence writes and reads its keys and values.
+pub struct KvStep {
+    // (from, to) blocks to copy first, kept alive until then
+    copies: Vec<(Arc<KvBlock>, Arc<KvBlock>)>,
+    // runs of new positions: (first pool slot, first new position, positions)
+    writes: Vec<(Slot, usize, usize)>,
+    // the slots of the positions to attend to within each chunk they are in: (chunk, slots)
+    reads: Vec<(usize, Tensor)>,
+    // puts the positions gathered chunk by chunk back in order, oldest first, when they are
+    // spread over several chunks
+    order: Option<Tensor>,
+    chunk_blocks: usize,
+}
+
+// A position in the pools: (chunk, slot within the chunk)
+type Slot = (usize, usize);
+
+impl KvStep {
+    fn slot(&self, block: &KvBlock) -> Slot {
+        let id = block.id as usize;
+        (id / self.chunk_blocks, id % self.chunk_blocks * BLOCK_SIZE)
+    }
+}
+
+impl BlockTable {
+    /// A table over `blocks`, all full.
+    pub fn from_blocks(blocks: Vec<Arc<KvBlock>>) -> Self {
+        let len = blocks.len() * BLOCK_SIZE;
+        BlockTable { blocks: blocks.into_iter().map(Some).collect(), len, copies: Vec::new() }
+    }
+
+    /// The number of positions written, i.e. the position of the next token.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Block `i`, when it is full and still held.
+    pub fn full_block(&self, i: usize) -> Option<&Arc<KvBlock>> {
+        match (i + 1) * BLOCK_SIZE <= self.len {
+            true => self.blocks.get(i)?.as_ref(),
+            false => None,
+        }
+    }
+
+    /// The memory of the blocks held, counting shared blocks in full.
+    pub fn size_in_bytes(&self) -> usize {
+        self.blocks.iter().flatten().map(|block| block.size_in_bytes()).sum()
+    }
+
+    /// Keeps the first `len` positions. `false` when the window already dropped some of them.
+    pub fn truncate(&mut self, len: usize) -> bool {
+        let blocks = len.div_ceil(BLOCK_SIZE);
+        if len > self.len || self.blocks[..blocks].iter().any(Option::is_none) {
+            return false;
+        }
+        self.blocks.truncate(blocks);
+        self.len = len;
+        true
+    }
+
+    /// Takes the blocks to grow to `len` positions, copying the block the next position goes
+    /// into when it is shared. `false` when the pool ran out of blocks.
+    pub fn reserve(&mut self, len: usize, allocator: &BlockAllocator) -> bool {
+        if !self.len.is_multiple_of(BLOCK_SIZE)
+            && let Some(Some(block)) = self.blocks.get(self.len / BLOCK_SIZE)
+            && Arc::strong_count(block) > 1
+        {
+            let Some(copy) = allocator.allocate() else {
+                return false;
+            };
+            let block = self.blocks[self.len / BLOCK_SIZE].replace(copy.clone());
+            self.copies.push((block.unwrap(), copy));
+        }
+        while self.blocks.len() < len.div_ceil(BLOCK_SIZE) {
+            match allocator.allocate() {
+                Some(block) => self.blocks.push(Some(block)),
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Prepares writing the next `t` positions, which attend to the positions before them as
+    /// far back as the `window` reaches; blocks before that are given back.
+    pub fn step(&mut self, t: usize, window: Option<usize>, allocator: &BlockAllocator, device: &Device) -> Result<KvStep> {
+        let start = self.len;
+        let read_from = window.map_or(0, |window| start.saturating_sub(window));
+        for block in self.blocks.iter_mut().take(read_from / BLOCK_SIZE) {
+            *block = None;
+        }
+        if !self.reserve(start + t, allocator) {
+            candle_core::bail!("the kv cache is full");
+        }
+        let chunk_blocks = allocator.chunk_blocks();
+        let slot = |position: usize| -> Result<Slot> {
+            match &self.blocks[position / BLOCK_SIZE] {
+                Some(block) => {
+                    let id = block.id as usize;
+                    Ok((id / chunk_blocks, id % chunk_blocks * BLOCK_SIZE + position % BLOCK_SIZE))
+                }
+                None => candle_core::bail!("position {position} fell out of the kv window"),
+            }
+        };
+        let mut writes = Vec::new();
+        let mut position = start;
+        while position < start + t {
+            let run = (BLOCK_SIZE - position % BLOCK_SIZE).min(start + t - position);
+            writes.push((slot(position)?, position - start, run));
+            position += run;
+        }
+
+        // the slots of every chunk, and where each position ends up when they are gathered
+        // chunk after chunk
+        let mut chunks: BTreeMap<usize, Vec<u32>> = BTreeMap::new();
+        let mut gathered = Vec::with_capacity(start + t - read_from);
+        for position in read_from..start + t {
+            let (chunk, slot) = slot(position)?;
+            let slots = chunks.entry(chunk).or_default();
+            gathered.push((chunk, slots.len()));
+            slots.push(slot as u32);
+        }
+        let order = match chunks.len() {
+            0 | 1 => None,
+            _ => {
+                let mut offsets = BTreeMap::new();
+                let mut offset = 0;
+                for (&chunk, slots) in &chunks {
+                    offsets.insert(chunk, offset);
+                    offset += slots.len() as u32;
+                }
+                let order: Vec<u32> = gathered.iter().map(|&(chunk, i)| offsets[&chunk] + i as u32).collect();
+                Some(Tensor::new(order, device)?)
+            }
+        };
+        let reads = chunks
+            .into_iter()
+            .map(|(chunk, slots)| Ok((chunk, Tensor::new(slots, device)?)))
+            .collect::<Result<Vec<_>>>()?;
+        self.len = start + t;
+        Ok(KvStep { copies: std::mem::take(&mut self.copies), writes, reads, order, chunk_blocks })
+    }
+}
+
+/// How the pools store keys and values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum KvCacheDtype {
+    /// As computed, 4 bytes per value.
+    F32,
+    /// Int8 with an f32 scale per head and position, dequantized when attention reads it: about
+    /// a quarter of the memory, at a small loss of accuracy.
+    Int8,
+}
+
+impl KvCacheDtype {
+    /// The memory of the keys or values of one head at one position.
+    pub fn head_bytes(&self, head_dim: usize) -> usize {
+        match self {
+            KvCacheDtype::F32 => head_dim * 4,
+            KvCacheDtype::Int8 => head_dim + 4,
+        }
+    }
+}
+
+// The keys or values of one chunk of blocks for one layer, shape `(n_kv_head, slots, head_dim)`
+#[derive(Debug)]
+struct PoolTensor {
+    // f32, or int8 offset by 128 to fit into u8 when quantized
+    values: Tensor,
+    // the scale of every head and position when quantized, shape `(n_kv_head, slots, 1)`
+    scales: Option<Tensor>,
+}
+
+impl PoolTensor {
+    fn new(n_kv_head: usize, head_dim: usize, slots: usize, dtype: KvCacheDtype, device: &Device) -> Result<Self> {
+        Ok(match dtype {
+            KvCacheDtype::F32 => PoolTensor {
+                values: Tensor::zeros((n_kv_head, slots, head_dim), DType::F32, device)?,
+                scales: None,
+            },
+            KvCacheDtype::Int8 => PoolTensor {
+                values: Tensor::zeros((n_kv_head, slots, head_dim), DType::U8, device)?,
+                scales: Some(Tensor::zeros((n_kv_head, slots, 1), DType::F32, device)?),
+            },
+        })
+    }
+
+    // Copies the block at slot `from` of `source`, which may be this chunk, to slot `to`
+    fn copy_block(&self, source: &PoolTensor, from: usize, to: usize) -> Result<()> {
+        // a copy first, reading and writing the same storage at once would deadlock
+        let copy = |x: &Tensor, source: &Tensor| {
+            x.slice_set(&source.narrow(1, from, BLOCK_SIZE)?.force_contiguous()?, 1, to)
+        };
+        copy(&self.values, &source.values)?;
+        if let (Some(scales), Some(source)) = (&self.scales, &source.scales) {
+            copy(scales, source)?;
+        }
+        Ok(())
+    }
+
+    // Writes `x`, shape `(n_kv_head, run, head_dim)`, from slot `slot` on
+    fn write(&self, x: &Tensor, slot: usize) -> Result<()> {
+        match &self.scales {
+            None => self.values.slice_set(&x.contiguous()?, 1, slot),
+            Some(scales) => {
+                let x = x.to_dtype(DType::F32)?;
+                let scale = (x.abs()?.max_keepdim(2)? / 127.)?.maximum(f32::MIN_POSITIVE)?;
+                let q = (x.broadcast_div(&scale)?.round()? + 128.)?.to_dtype(DType::U8)?;
+                self.values.slice_set(&q, 1, slot)?;
+                scales.slice_set(&scale, 1, slot)
+            }
+        }
+    }
+
+    fn gather(&self, reads: &Tensor) -> Result<Tensor> {
+        let values = self.values.index_select(reads, 1)?;
+        match &self.scales {
+            None => Ok(values),
+            Some(scales) => (values.to_dtype(DType::F32)? - 128.)?.broadcast_mul(&scales.index_select(reads, 1)?),
+        }
+    }
+}
+
+/// The keys and values of every block for one layer, in chunks of `BlockAllocator::chunk_blocks`
+/// blocks that are added as the allocator hands out more blocks.
+#[derive(Debug)]
+pub struct LayerPool {
+    n_kv_head: usize,
+    head_dim: usize,
+    dtype: KvCacheDtype,
+    device: Device,
+    k: Vec<PoolTensor>,
+    v: Vec<PoolTensor>,
+}
+
+impl LayerPool {
+    pub fn new(n_kv_head: usize, head_dim: usize, dtype: KvCacheDtype, device: &Device) -> Self {
+        LayerPool { n_kv_head, head_dim, dtype, device: device.clone(), k: Vec::new(), v: Vec::new() }
+    }
+
+    /// Adds chunks until the pool holds every block the allocator handed out. Meant to run
+    /// between forward passes, which fail on blocks the pool does not hold yet.
+    pub fn grow(&mut self, allocator: &BlockAllocator) -> Result<()> {
+        let chunk_blocks = allocator.chunk_blocks();
+        while self.k.len() * chunk_blocks < allocator.created() {
+            // the last chunk stops at the cap
+            let blocks = chunk_blocks.min(allocator.max_blocks() - self.k.len() * chunk_blocks);
+            let slots = blocks * BLOCK_SIZE;
+            let chunk = || PoolTensor::new(self.n_kv_head, self.head_dim, slots, self.dtype, &self.device);
+            let (k, v) = (chunk()?, chunk()?);
+            self.k.push(k);
+            self.v.push(v);
+        }
+        Ok(())
+    }
+
+    fn holds(&self, (chunk, slot): Slot) -> bool {
+        self.k.get(chunk).is_some_and(|k| slot < k.values.dims()[1])
+    }
+
+    /// Writes the keys and values of a step, shape `(1, n_kv_head, t, head_dim)`, and returns the
+    /// keys and values it attends to in f32, shape `(1, n_kv_head, positions, head_dim)`.
+    pub fn write_and_gather(&mut self, step: &KvStep, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
+        let blocks = step.copies.iter().flat_map(|(from, to)| [step.slot(from), step.slot(to)]);
+        let writes = step.writes.iter().map(|&(slot, _, _)| slot);
+        if let Some(slot) = blocks.chain(writes).find(|&slot| !self.holds(slot)) {
+            candle_core::bail!("the kv cache pool does not hold slot {slot:?} yet, grow it first");
+        }
+        for (from, to) in &step.copies {
+            let ((from_chunk, from), (to_chunk, to)) = (step.slot(from), step.slot(to));
+            self.k[to_chunk].copy_block(&self.k[from_chunk], from, to)?;
+            self.v[to_chunk].copy_block(&self.v[from_chunk], from, to)?;
+        }
+        let (k, v) = (k.squeeze(0)?, v.squeeze(0)?);
+        for &((chunk, slot), offset, run) in &step.writes {
+            self.k[chunk].write(&k.narrow(1, offset, run)?, slot)?;
+            self.v[chunk].write(&v.narrow(1, offset, run)?, slot)?;
+        }
+        let gather = |pool: &[PoolTensor]| -> Result<Tensor> {
+            let gathered = step
+                .reads
+                .iter()
+                .map(|(chunk, slots)| pool[*chunk].gather(slots))
+                .collect::<Result<Vec<_>>>()?;
+            let gathered = Tensor::cat(&gathered, 1)?;
+            match &step.order {
+                Some(order) => gathered.index_select(order, 1)?.unsqueeze(0),
+                None => gathered.unsqueeze(0),
+            }
+        };
+        Ok((gather(&self.k)?, gather(&self.v)?))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs `values`, one per position, through `table` and returns every position it attends to
+    fn write(pool: &mut LayerPool, table: &mut BlockTable, values: &[f32], allocator: &BlockAllocator) -> Vec<f32> {
+        let step = table.step(values.len(), None, allocator, &Device::Cpu).unwrap();
+        pool.grow(allocator).unwrap();
+        let x = Tensor::new(values, &Device::Cpu).unwrap().reshape((1, 1, values.len(), 1)).unwrap();
+        let (k, _) = pool.write_and_gather(&step, &x, &x).unwrap();
+        k.flatten_all().unwrap().to_vec1().unwrap()
+    }
+
+    #[test]
+    fn a_fork_copies_a_shared_block_before_writing_into_it() {
+        let allocator = BlockAllocator::new(8, 8);
+        let mut pool = LayerPool::new(1, 1, KvCacheDtype::F32, &Device::Cpu);
+        let mut table = BlockTable::default();
+        write(&mut pool, &mut table, &[1.; 10], &allocator);
+
+        let mut fork = table.clone();
+        assert_eq!(allocator.used(), 1);
+        let seen = write(&mut pool, &mut fork, &[2.], &allocator);
+        assert_eq!(seen, [[1.; 10].as_slice(), &[2.]].concat());
+        assert_eq!(allocator.used(), 2);
+
+        // the original block is no longer shared, so it is written in place
+        let seen = write(&mut pool, &mut table, &[3.], &allocator);
+        assert_eq!(seen, [[1.; 10].as_slice(), &[3.]].concat());
+        assert_eq!(allocator.used(), 2);
+        let seen = write(&mut pool, &mut fork, &[4.], &allocator);
+        assert_eq!(seen, [[1.; 10].as_slice(), &[2., 4.]].concat());
+    }
+
+    #[test]
+    fn positions_spread_over_chunks_are_gathered_in_order() {
+        // two blocks per chunk
+        let allocator = BlockAllocator::new(8, CHUNK_BYTES / 2);
+        assert_eq!(allocator.chunk_blocks(), 2);
+        let mut table = BlockTable::default();
+        assert!(table.reserve(3 * BLOCK_SIZE, &allocator));
+        drop(table);
+
+        // blocks 2, 1 and 0, i.e. the second chunk before the first
+        let mut pool = LayerPool::new(1, 1, KvCacheDtype::F32, &Device::Cpu);
+        let mut table = BlockTable::default();
+        let values: Vec<f32> = (0..40).map(|i| i as f32).collect();
+        assert_eq!(write(&mut pool, &mut table, &values, &allocator), values);
+        let seen = write(&mut pool, &mut table, &[40.], &allocator);
+        assert_eq!(seen, (0..41).map(|i| i as f32).collect::<Vec<_>>());
+        assert_eq!(pool.k.len(), 2);
+    }
+
+    #[test]
+    fn a_pass_fails_on_blocks_the_pool_does_not_hold() {
+        let allocator = BlockAllocator::new(8, 8);
+        let mut pool = LayerPool::new(1, 1, KvCacheDtype::F32, &Device::Cpu);
+        let mut table = BlockTable::default();
+        let step = table.step(1, None, &allocator, &Device::Cpu).unwrap();
+        let x = Tensor::zeros((1, 1, 1, 1), DType::F32, &Device::Cpu).unwrap();
+        assert!(pool.write_and_gather(&step, &x, &x).is_err());
+        pool.grow(&allocator).unwrap();
+        assert!(pool.write_and_gather(&step, &x, &x).is_ok());
+    }
+
+    #[test]
+    fn truncate_fails_once_the_window_dropped_the_blocks() {
+        let allocator = BlockAllocator::new(8, 8);
+        let mut table = BlockTable::default();
+        table.step(40, None, &allocator, &Device::Cpu).unwrap();
+        assert!(!table.truncate(41));
+        assert!(table.truncate(36));
+        // attends to positions 28..37, so block 0 is given back
+        table.step(1, Some(8), &allocator, &Device::Cpu).unwrap();
+        assert_eq!(allocator.used(), 2);
+        assert!(!table.truncate(10));
+        assert!(!table.truncate(35));
+        assert_eq!(table.len(), 37);
+    }
+
+    #[test]
+    fn blocks_go_back_to_the_allocator_when_dropped() {
+        let allocator = BlockAllocator::new(4, 8);
+        let mut table = BlockTable::default();
+        assert!(table.reserve(4 * BLOCK_SIZE, &allocator));
+        assert_eq!((allocator.used(), allocator.free()), (4, 0));
+        assert!(allocator.allocate().is_none());
+        assert!(!table.clone().reserve(5 * BLOCK_SIZE, &allocator));
+
+        let fork = table.clone();
+        drop(table);
+        assert_eq!(allocator.used(), 4);
+        drop(fork);
+        assert_eq!((allocator.used(), allocator.free()), (0, 4));
+        assert!(allocator.allocate().is_some());
+        assert_eq!(allocator.created(), 4);
+    }
+}
@@ -8,10 +8,17 @@ extern crate intel_mkl_src;
 extern crate accelerate_src;
 
 
-use clap::{Parser, ValueEnum};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum};
 
 use anyhow;
-use tokenizers::Tokenizer;
+
+use super::kv_cache::KvCacheDtype;
+use super::params::Truncation;
 
 
 pub const DEFAULT_PROMPT: &str = "Write a function to count prime numbers up to N. ";
@@ -38,7 +45,16 @@ pub enum Which {
     W25_14bQ8,
 }
 
-#[derive(Parser, Debug)]
+impl Which {
+    /// The name accepted by `--which`, e.g. "7b"; also reported as the model id over the API.
+    pub fn name(&self) -> String {
+        self.to_possible_value()
+            .map(|v| v.get_name().to_string())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
     /// GGUF file to load, typically a .gguf file generated by the quantize command from llama.cpp
@@ -51,6 +67,10 @@ pub struct Args {
     #[arg(long)]
     pub prompt: Option<String>,
 
+    /// System prompt for chat requests whose conversation does not start with a system message.
+    #[arg(long)]
+    pub system_prompt: Option<String>,
+
     /// The length of the sample to generate (in tokens).
     #[arg(short = 'n', long, default_value_t = 1000)]
     pub sample_len: usize,
@@ -75,14 +95,20 @@ pub struct Args {
     #[arg(long, default_value_t = 299792458)]
     pub seed: u64,
 
-    /// Enable tracing (generates a trace-timestamp.json file).
+    /// Enable tracing (generates a trace-timestamp.json file, written on Ctrl-C, and logs spans as JSON lines).
     #[arg(long)]
     pub tracing: bool,
 
-    /// Process prompt elements separately.
+    /// Process prompt elements separately, i.e. prefill in chunks of one token.
     #[arg(long)]
     pub split_prompt: bool,
 
+    /// Prefill runs prompts through the model in chunks of this many tokens, one chunk per
+    /// engine step, so long prompts take less memory at once and do not stall the decoding of
+    /// other requests for long. 0 runs the whole prompt at once.
+    #[arg(long, default_value_t = 512)]
+    pub prefill_chunk_size: usize,
+
     /// Run on CPU rather than GPU even if a GPU is available.
     #[arg(long)]
     pub cpu: bool,
@@ -95,13 +121,165 @@ pub struct Args {
     #[arg(long, default_value_t = 64)]
     pub repeat_last_n: usize,
 
+    /// What to do with prompts that do not fit into the context window of the model;
+    /// requests may override it with `truncation`.
+    #[arg(long, value_enum, default_value = "error")]
+    pub truncation: Truncation,
+
+    /// Upper bound for `max_tokens` of a request.
+    #[arg(long, default_value_t = 4096)]
+    pub max_tokens_limit: usize,
+
+    /// Wall-clock limit of a request in seconds, counted from when it is queued; requests may ask
+    /// for less with `timeout`. 0 disables the limit.
+    #[arg(long, default_value_t = 300)]
+    pub request_timeout_secs: u64,
+
+    /// Maximum number of sequences decoded together in one batched forward pass.
+    #[arg(long, default_value_t = 8)]
+    pub max_batch_size: usize,
+
+    /// Instead of serving, decode this many tokens greedily after `--prompt` with the default
+    /// model and print the decode speed as the kv cache grows, then exit.
+    #[arg(long)]
+    pub bench_decode: Option<usize>,
+
+    /// Keep the keys and values of only the last this many positions of each sequence, in a
+    /// ring buffer: bounds the kv cache memory, but attention only looks back that far.
+    #[arg(long)]
+    pub kv_window: Option<NonZeroUsize>,
+
+    /// Maximum number of requests waiting for a model; more are turned away with 503.
+    #[arg(long, default_value_t = 64)]
+    pub max_queue: usize,
+
+    /// Requests that waited this many seconds without being started are turned away with 503.
+    #[arg(long, default_value_t = 60)]
+    pub max_queue_wait_secs: u64,
+
+    /// Chat sessions idle for longer than this many seconds are deleted.
+    #[arg(long, default_value_t = 1800)]
+    pub session_ttl_secs: u64,
+
+    /// Maximum number of chat sessions kept; the least recently used one is deleted beyond it.
+    #[arg(long, default_value_t = 64)]
+    pub max_sessions: usize,
+
+    /// Memory budget in MB for the kv caches kept by chat sessions.
+    #[arg(long, default_value_t = 1024)]
+    pub session_cache_mb: usize,
+
+    /// Memory budget in MB per model for the kv cache blocks of all its sequences, chat sessions
+    /// and cached prefixes; prompts that do not fit get 503.
+    #[arg(long, default_value_t = 4096)]
+    pub kv_cache_mb: usize,
+
+    /// How the kv cache stores keys and values: `int8` takes about a quarter of the memory of
+    /// `f32`, so `--kv-cache-mb` holds about four times the positions, at a small loss of accuracy.
+    #[arg(long, value_enum, default_value = "f32")]
+    pub kv_cache_dtype: KvCacheDtype,
+
+    /// Memory budget in MB per model for the keys and values of prompt prefixes shared across
+    /// requests (e.g. a system prompt), which then skip their prefill; 0 disables the cache.
+    #[arg(long, default_value_t = 512)]
+    pub prefix_cache_mb: usize,
+
     /// The model size to use.
     #[arg(long, default_value = "0.5b")]
     pub which: Which,
+
+    /// Model registry (.toml or .json) listing the local GGUF and tokenizer files of each model;
+    /// with it `--model`, `--tokenizer` and `--which` are ignored and nothing is downloaded.
+    #[arg(long)]
+    pub registry: Option<String>,
+
+    /// The model serving requests that do not name one, defaults to the registry's `default`.
+    /// It is loaded at startup, the other registered models on first use.
+    #[arg(long)]
+    pub model_id: Option<String>,
+
+    /// Maximum number of models loaded at once; loading another one unloads the least recently used.
+    #[arg(long, default_value_t = 2)]
+    pub max_loaded_models: usize,
+
+    /// Models other than the default one are unloaded after this many seconds without requests.
+    #[arg(long, default_value_t = 600)]
+    pub model_idle_secs: u64,
+
+    /// Address to listen on; 0.0.0.0 accepts connections from other hosts.
+    #[arg(long, default_value = "127.0.0.1")]
+    pub host: IpAddr,
+
+    #[arg(long, default_value_t = 8000)]
+    pub port: u16,
+
+    /// PEM certificate chain; together with `--tls-key` the server speaks HTTPS only.
+    #[arg(long)]
+    pub tls_cert: Option<String>,
+
+    /// PEM private key of `--tls-cert`.
+    #[arg(long)]
+    pub tls_key: Option<String>,
+
+    /// API keys file (.toml or .json, see `keys.example.toml`); with it every endpoint but the
+    /// probes and `/metrics` takes `Authorization: Bearer <key>` and keys are rate limited.
+    #[arg(long)]
+    pub api_keys: Option<String>,
+
+    /// TOML file giving values to any of these options, e.g. `port = 8080`; options on the
+    /// command line and `WARP_LLM_<OPTION>` environment variables take precedence over it.
+    #[arg(long)]
+    pub config: Option<String>,
+}
+
+/// Prefix of the environment variables that set options, e.g. `WARP_LLM_PORT=8080`.
+pub const ENV_PREFIX: &str = "WARP_LLM_";
+
+// The clap command with every option also read from its environment variable
+fn command() -> clap::Command {
+    Args::command().mut_args(|arg| {
+        let env = format!("{}{}", ENV_PREFIX, arg.get_id().as_str().to_uppercase());
+        arg.env(env)
+    })
 }
 
 impl Args {
-    pub fn tokenizer(&self) -> anyhow::Result<Tokenizer> {
+    /// Parses the options from the command line, the environment and the `--config` file,
+    /// in that order of precedence, then the built-in defaults.
+    pub fn load() -> anyhow::Result<Args> {
+        let mut command = command();
+        let matches = command.clone().get_matches();
+        if let Some(path) = matches.get_one::<String>("config") {
+            let text = std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("{}: {}", path, e))?;
+            let table: toml::Table = toml::from_str(&text).map_err(|e| anyhow::anyhow!("{}: {}", path, e))?;
+            for (key, value) in table {
+                // `max-batch-size` and `max_batch_size` both name --max-batch-size
+                let id = key.replace('-', "_");
+                if id == "config" || !command.get_arguments().any(|arg| arg.get_id() == id.as_str()) {
+                    anyhow::bail!("{}: unknown option `{}`", path, key);
+                }
+                let value = match value {
+                    toml::Value::String(s) => s,
+                    toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_) => value.to_string(),
+                    other => anyhow::bail!("{}: `{}` must be a string, number or boolean, got {}", path, key, other),
+                };
+                // config values take the place of the defaults, so flags and env vars still win
+                command = command.mut_arg(id, |arg| arg.default_value(value));
+            }
+        }
+        let args = Args::from_arg_matches(&command.get_matches())?;
+        if args.kv_cache_mb == 0 {
+            anyhow::bail!("kv-cache-mb must be at least 1");
+        }
+        // checked here rather than with `requires`, which clap does not apply to config values
+        // as it takes them for defaults
+        if args.tls_cert.is_some() != args.tls_key.is_some() {
+            anyhow::bail!("tls-cert and tls-key must be given together");
+        }
+        Ok(args)
+    }
+
+    pub fn tokenizer_path(&self) -> anyhow::Result<std::path::PathBuf> {
         let tokenizer_path = match &self.tokenizer {
             Some(config) => std::path::PathBuf::from(config),
             None => {
@@ -120,7 +298,7 @@ impl Args {
                 api.get("tokenizer.json")?
             }
         };
-        Tokenizer::from_file(tokenizer_path).map_err(anyhow::Error::msg)
+        Ok(tokenizer_path)
     }
 
     pub fn model(&self) -> anyhow::Result<std::path::PathBuf, Box<dyn std::error::Error>> {
@@ -194,3 +372,24 @@ pub fn format_size(size_in_bytes: usize) -> String {
     }
 }
 
+/// An id other clients cannot guess, e.g. `sess-` followed by 32 hex digits for a `sess-` prefix.
+pub fn random_id(prefix: &str) -> String {
+    // RandomState is seeded from the OS, which is enough for unguessable ids without pulling in `rand`
+    let random = || RandomState::new().build_hasher().finish();
+    format!("{}{:016x}{:016x}", prefix, random(), random())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_ids_carry_their_prefix_and_differ() {
+        let (a, b) = (random_id("sess-"), random_id("sess-"));
+        assert_ne!(a, b);
+        let digits = a.strip_prefix("sess-").unwrap();
+        assert_eq!(digits.len(), 32);
+        assert!(digits.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+}
@@ -1,15 +1,25 @@
 
 
-use std::{io::Write, io::Result, vec};
+use super::error::LlmError;
+
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::time::Instant;
 
 use candle_core::quantized::gguf_file;
-use candle_examples::token_output_stream::TokenOutputStream as TokenOutputStream;
-use candle_core::Tensor;
-use candle_transformers::generation::{LogitsProcessor, Sampling};
+use candle_core::{D, Tensor};
+pub use candle_examples::token_output_stream::TokenOutputStream as TokenOutputStream;
+use serde_derive::{Deserialize, Serialize};
+use tokenizers::Tokenizer;
 
 use super::llm as llm;
 use super::llm::Args as Args;
-use super::quantized_qwen2_copy::ModelWeights as Qwen2; 
+use super::chat::{ChatMessage, ChatTemplate, Role};
+use super::metrics::METRICS;
+use super::registry::ModelEntry;
+use super::kv_cache::BlockTable;
+pub use super::quantized_qwen2_copy::ModelWeights as Qwen2; 
 // use super::quantized_qwen2_copy::LayerWeights;
 
 pub fn print_setup(args: &Args) {
@@ -27,171 +37,230 @@ pub fn print_setup(args: &Args) {
 } 
 
 
-pub fn build_model(args: &Args) -> Result<(Qwen2, TokenOutputStream)> {
-    let model_path = args.model().unwrap();
-    let mut file = std::fs::File::open(&model_path).unwrap();
+// Bytes taken by the (quantized) weights
+fn tensors_size(content: &gguf_file::Content) -> usize {
+    content
+        .tensor_infos
+        .values()
+        .map(|tensor| tensor.shape.elem_count() * tensor.ggml_dtype.type_size() / tensor.ggml_dtype.block_size())
+        .sum()
+}
+
+/// What the header of a GGUF file says about the model; the weights are not read.
+#[derive(Debug, Clone, Serialize)]
+pub struct GgufInfo {
+    pub architecture: String,
+    pub context_length: Option<u64>,
+    pub embedding_length: Option<u64>,
+    pub block_count: Option<u64>,
+    pub head_count: Option<u64>,
+    pub head_count_kv: Option<u64>,
+    /// The tensor type holding most of the weights, e.g. "Q4_0".
+    pub quantization: String,
+    pub tensor_count: usize,
+    pub size_in_bytes: usize,
+    /// `size_in_bytes` for humans, e.g. "4.43GB".
+    pub size: String,
+}
+
+/// Reads the metadata and the tensor table of a GGUF file.
+pub fn gguf_info(path: &Path) -> Result<GgufInfo, LlmError> {
+    let load_error = |e: &dyn std::fmt::Display| LlmError::ModelLoad(format!("{}: {}", path.display(), e));
+    let mut file = std::fs::File::open(path).map_err(|e| load_error(&e))?;
+    let content = gguf_file::Content::read(&mut file).map_err(|e| load_error(&e))?;
+
+    let architecture = match content.metadata.get("general.architecture").map(|v| v.to_string()) {
+        Some(Ok(architecture)) => architecture.clone(),
+        _ => return Err(load_error(&"no general.architecture in the metadata")),
+    };
+    // the model parameters are stored under the architecture, e.g. qwen2.context_length
+    let number = |key: &str| {
+        let key = format!("{}.{}", architecture, key);
+        content.metadata.get(&key).and_then(|v| v.to_u64().ok())
+    };
+    let mut bytes_by_type: HashMap<String, usize> = HashMap::new();
+    for tensor in content.tensor_infos.values() {
+        let bytes = tensor.shape.elem_count() * tensor.ggml_dtype.type_size() / tensor.ggml_dtype.block_size();
+        *bytes_by_type.entry(format!("{:?}", tensor.ggml_dtype)).or_default() += bytes;
+    }
+    let quantization = bytes_by_type
+        .into_iter()
+        .max_by_key(|(_, bytes)| *bytes)
+        .map(|(dtype, _)| dtype)
+        .unwrap_or_default();
+    let size_in_bytes = tensors_size(&content);
+
+    Ok(GgufInfo {
+        context_length: number("context_length"),
+        embedding_length: number("embedding_length"),
+        block_count: number("block_count"),
+        head_count: number("attention.head_count"),
+        head_count_kv: number("attention.head_count_kv"),
+        architecture,
+        quantization,
+        tensor_count: content.tensor_infos.len(),
+        size_in_bytes,
+        size: llm::format_size(size_in_bytes),
+    })
+}
+
+/// Loads the weights and the tokenizer of a registry entry from local files.
+pub fn build_model(entry: &ModelEntry, cpu: bool) -> Result<(Qwen2, Tokenizer), LlmError> {
+    let load_error = |e: &dyn std::fmt::Display| LlmError::ModelLoad(e.to_string());
+    let model_path = entry.gguf.clone();
+    let mut file = std::fs::File::open(&model_path)
+        .map_err(|e| LlmError::ModelLoad(format!("{}: {}", model_path.display(), e)))?;
     let start = std::time::Instant::now();
-    let device = candle_examples::device(args.cpu).unwrap();
+    let device = candle_examples::device(cpu).map_err(|e| load_error(&e))?;
 
     let model = {
-        let model = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(model_path)).unwrap();
-        let mut total_size_in_bytes = 0;
-        for (_, tensor) in model.tensor_infos.iter() {
-            let elem_count = tensor.shape.elem_count();
-            total_size_in_bytes +=
-                elem_count * tensor.ggml_dtype.type_size() / tensor.ggml_dtype.block_size();
-        }
+        let model = gguf_file::Content::read(&mut file)
+            .map_err(|e| load_error(&e.with_path(model_path)))?;
+        let total_size_in_bytes = tensors_size(&model);
         println!(
             "loaded {:?} tensors ({}) in {:.2}s",
             model.tensor_infos.len(),
             llm::format_size(total_size_in_bytes),
             start.elapsed().as_secs_f32(),
         );
-        Qwen2::from_gguf(model, &mut file, &device).unwrap()
+        Qwen2::from_gguf(model, &mut file, &device).map_err(|e| load_error(&e))?
     };
     println!("model built");
+    METRICS.model_load.with_label_values(&[&entry.id]).observe(start.elapsed().as_secs_f64());
 
-    let tokenizer = args.tokenizer().unwrap();
-    let tos = TokenOutputStream::new(tokenizer);
+    let tokenizer = Tokenizer::from_file(&entry.tokenizer)
+        .map_err(|e| LlmError::ModelLoad(format!("{}: {}", entry.tokenizer.display(), e)))?;
 
-    Ok((model, tos))
+    Ok((model, tokenizer))
 }
 
 
+// the decode speed is reported for every this many tokens
+const BENCH_SEGMENT: usize = 256;
 
-pub fn run_model(model: &mut Qwen2, tos: &mut TokenOutputStream, args: &Args, prompt:Option<&String>) 
-    -> Result<String> {
-    let device = candle_examples::device(args.cpu).unwrap();
-    // let prompt_str = match prompt {
-    //     Some(p) => prompt.clone(),
-    //     // None => Some(&args.prompt.clone()
-    //     //                 .unwrap_or_else(|| llm::DEFAULT_PROMPT.to_string())),
-    //     None => { 
-    //         let p = args.prompt.clone().unwrap_or_else(|| llm::DEFAULT_PROMPT.to_string());
-    //         Some(&p)
-    //     }
-    // };
-    let prompt_str0 = args.prompt.clone().unwrap_or_else(|| llm::DEFAULT_PROMPT.to_string());
-    let prompt_str = if let Some(p) = prompt {
-        p.clone()
-    } else {
-        prompt_str0.clone()
-    };
-    let prompt_str = match args.which {
-        llm::Which::DeepseekR1Qwen7B => format!("<｜User｜>{prompt_str}<｜Assistant｜>"),
-        _ => format!("<|im_start|>user\n{prompt_str}<|im_end|>\n<|im_start|>assistant\n"),
-    };
-    print!("formatted instruct prompt: {}", &prompt_str);
-
-    let tokens = tos
-        .tokenizer()
-        .encode(prompt_str, true)
-        .map_err(anyhow::Error::msg).unwrap();
-    let tokens = tokens.get_ids();
-    let to_sample = args.sample_len.saturating_sub(1);
-    let mut all_tokens = vec![];
-    let mut logits_processor = {
-        let temperature = args.temperature;
-        let sampling = if temperature <= 0. {
-            Sampling::ArgMax
-        } else {
-            match (args.top_k, args.top_p) {
-                (None, None) => Sampling::All { temperature },
-                (Some(k), None) => Sampling::TopK { k, temperature },
-                (None, Some(p)) => Sampling::TopP { p, temperature },
-                (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
-            }
-        };
-        LogitsProcessor::from_sampling(args.seed, sampling)
-    };
-    let start_prompt_processing = std::time::Instant::now();
-    let mut next_token = if !args.split_prompt {
-        let input = Tensor::new(tokens, &device).unwrap().unsqueeze(0).unwrap();
-        let logits = model.forward(&input, 0).unwrap();
-        let logits = logits.squeeze(0).unwrap();
-        logits_processor.sample(&logits).unwrap()
-    } else {
-        let mut next_token = 0;
-        for (pos, token) in tokens.iter().enumerate() {
-            let input = Tensor::new(&[*token], &device).unwrap().unsqueeze(0).unwrap();
-            let logits = model.forward(&input, pos).unwrap();
-            let logits = logits.squeeze(0).unwrap();
-            next_token = logits_processor.sample(&logits).unwrap()
+/// Decodes `tokens` tokens greedily after the CLI prompt, one at a time through the batched
+/// decode path of the engine, and prints the speed of every `BENCH_SEGMENT` of them to show
+/// how decoding holds up as the kv cache grows.
+pub fn bench_decode(entry: &ModelEntry, args: &Args, tokens: usize) -> Result<(), LlmError> {
+    let (mut model, tokenizer) = build_model(entry, args.cpu)?;
+    model.set_kv_window(args.kv_window.map(NonZeroUsize::get));
+    model
+        .set_kv_cache(args.kv_cache_mb * 1024 * 1024, args.kv_cache_dtype)
+        .map_err(|e| LlmError::ModelLoad(e.to_string()))?;
+    let device = candle_examples::device(args.cpu).map_err(|e| LlmError::ModelLoad(e.to_string()))?;
+    let prompt = format_prompt(args, entry.template, None);
+    let prompt = tokenizer.encode(prompt, true).map_err(|e| LlmError::Tokenizer(e.to_string()))?;
+    let prompt = prompt.get_ids();
+    let tokens = tokens.min(model.context_length().saturating_sub(prompt.len()));
+
+    // like the engine, the blocks of a pass are taken and the pools grown before running it
+    let reserve = |model: &mut Qwen2, kv: &mut BlockTable, t: usize| -> Result<(), LlmError> {
+        if !kv.reserve(kv.len() + t, model.block_allocator()) {
+            return Err(LlmError::KvCacheFull { tokens: kv.len() + t });
         }
-        next_token
+        Ok(model.grow_kv_cache()?)
     };
-    let prompt_dt = start_prompt_processing.elapsed();
-    all_tokens.push(next_token);
-    let mut str_output = String::from("");
-    if let Some(t) = tos.next_token(next_token).unwrap() {
-        print!("{t}");
-        str_output += &t; 
-        std::io::stdout().flush().unwrap();
+
+    let input = Tensor::new(prompt, &device)?.unsqueeze(0)?;
+    let mut kv = BlockTable::default();
+    reserve(&mut model, &mut kv, prompt.len())?;
+    let logits = model.forward(&input, &mut kv)?.squeeze(0)?;
+    let mut next = logits.argmax(D::Minus1)?.to_scalar::<u32>()?;
+    println!("decoding {} tokens after a prompt of {}", tokens, prompt.len());
+    let mut segment_start = Instant::now();
+    for i in 0..tokens {
+        reserve(&mut model, &mut kv, 1)?;
+        let logits = model.forward_batch(&[next], &mut [&mut kv])?;
+        next = logits.get(0)?.argmax(D::Minus1)?.to_scalar::<u32>()?;
+        let decoded = i + 1;
+        if decoded % BENCH_SEGMENT == 0 || decoded == tokens {
+            let segment = (decoded - 1) % BENCH_SEGMENT + 1;
+            println!(
+                "{:6} tokens: {:8.2} token/s, kv cache {}",
+                decoded,
+                segment as f64 / segment_start.elapsed().as_secs_f64(),
+                llm::format_size(kv.size_in_bytes()),
+            );
+            segment_start = Instant::now();
+        }
     }
+    Ok(())
+}
 
-    let eos_token = match args.which {
-        llm::Which::DeepseekR1Qwen7B => "<｜end▁of▁sentence｜>",
-        _ => "<|im_end|>",
-    };
 
-    let eos_token = *tos.tokenizer().get_vocab(true).get(eos_token).unwrap();
-    let start_post_prompt = std::time::Instant::now();
-
-    let mut sampled = 0;
-    for index in 0..to_sample {
-        // in the backend, the model is using cache to add next token. 
-        // try clearing the kv_cache for all layers
-        // for layer in model.layers.iter_mut() {
-        //     layer.kv_cache = None; 
-        // }  
-
-        let input = Tensor::new(&[next_token], &device).unwrap().unsqueeze(0).unwrap();
-        let logits: Tensor = model.forward(&input, tokens.len() + index).unwrap();
-        let logits = logits.squeeze(0).unwrap();
-        let logits = if args.repeat_penalty == 1. {
-            logits
-        } else {
-            let start_at = all_tokens.len().saturating_sub(args.repeat_last_n);
-            candle_transformers::utils::apply_repeat_penalty(
-                &logits,
-                args.repeat_penalty,
-                &all_tokens[start_at..],
-            ).unwrap()
-        };
-        next_token = logits_processor.sample(&logits).unwrap();
-        all_tokens.push(next_token);
-        if let Some(t) = tos.next_token(next_token).unwrap() {
-            print!("{t}");
-            str_output += &t; 
-            std::io::stdout().flush().unwrap();
+/// Token counts and throughput of a single generation.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    /// Prompt tokens whose keys and values were already in the kv cache and skipped prefill.
+    pub cached_tokens: usize,
+    pub completion_tokens: usize,
+    pub prompt_tokens_per_sec: f64,
+    pub completion_tokens_per_sec: f64,
+}
+
+/// Why generation ended: the model emitted its eos token / the caller asked to stop ("stop"),
+/// `max_tokens` ran out ("length"), or one of the request's stop strings or stop token ids
+/// came up ("stop_sequence"), the request was cancelled or its client went away ("cancelled"),
+/// or its `timeout` ran out ("timeout").
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    Stop,
+    Length,
+    StopSequence,
+    Cancelled,
+    Timeout,
+}
+
+impl FinishReason {
+    /// The name used in responses, e.g. "stop_sequence".
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FinishReason::Stop => "stop",
+            FinishReason::Length => "length",
+            FinishReason::StopSequence => "stop_sequence",
+            FinishReason::Cancelled => "cancelled",
+            FinishReason::Timeout => "timeout",
         }
-        sampled += 1;
-        if next_token == eos_token {
-            break;
-        };
-    }
-    if let Some(rest) = tos.decode_rest().map_err(candle_core::Error::msg).unwrap() {
-        print!("{rest}");
-        str_output += &rest; 
     }
-    std::io::stdout().flush().unwrap();
-    let dt = start_post_prompt.elapsed();
-    println!(
-        "\n\n{:4} prompt tokens processed: {:.2} token/s",
-        tokens.len(),
-        tokens.len() as f64 / prompt_dt.as_secs_f64(),
-    );
-    println!(
-        "{sampled:4} tokens generated: {:.2} token/s",
-        sampled as f64 / dt.as_secs_f64(),
-    );
+}
 
-    // clear the kv_cache for all layers
-    for layer in model.layers.iter_mut() {
-            layer.kv_cache = None; 
-    }
+/// What was cut to fit a request into the context window of the model.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Truncated {
+    /// Tokens dropped from the start of the prompt (`"truncation": "left"`).
+    pub prompt_tokens: usize,
+    /// Messages left out of the conversation (`"truncation": "oldest_messages"`).
+    pub messages: usize,
+    /// The lowered `max_tokens` when the requested one did not fit the remaining window.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+}
 
-    Ok(str_output)
+/// The generated text together with its usage stats.
+#[derive(Debug, Clone)]
+pub struct Generation {
+    pub text: String,
+    pub usage: Usage,
+    pub finish_reason: FinishReason,
+    /// Set when the prompt or `max_tokens` had to be cut down.
+    pub truncated: Option<Truncated>,
 }
 
+/// Wraps a single user prompt (or the CLI prompt when `None`) in the instruct template of the model.
+pub fn format_prompt(args: &Args, template: ChatTemplate, prompt: Option<&String>) -> String {
+    let prompt_str0 = args.prompt.clone().unwrap_or_else(|| llm::DEFAULT_PROMPT.to_string());
+    let prompt_str = if let Some(p) = prompt {
+        p.clone()
+    } else {
+        prompt_str0.clone()
+    };
+    template.render(&[ChatMessage::new(Role::User, prompt_str)], None)
+}
 
+/// Renders a conversation with the template of the model, falling back to `--system-prompt`
+/// when the conversation has no system message of its own.
+pub fn format_chat(args: &Args, template: ChatTemplate, messages: &[ChatMessage]) -> String {
+    template.render(messages, args.system_prompt.as_deref())
+}
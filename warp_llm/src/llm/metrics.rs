@@ -0,0 +1,109 @@
+// Prometheus telemetry of the server, rendered by `GET /metrics`
+
+use std::sync::LazyLock;
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+
+
+pub struct Metrics {
+    registry: Registry,
+    /// HTTP responses by status code.
+    pub requests: IntCounterVec,
+    /// Finished generations by model and finish reason.
+    pub generations: IntCounterVec,
+    pub prompt_tokens: IntCounterVec,
+    /// Prompt tokens served from a kv cache instead of being prefilled.
+    pub cached_tokens: IntCounterVec,
+    pub completion_tokens: IntCounterVec,
+    /// From queueing a request to its first generated token.
+    pub time_to_first_token: HistogramVec,
+    /// Between two generated tokens of a sequence, including the prefill chunks run in between.
+    pub inter_token_latency: HistogramVec,
+    /// From queueing a request until the engine starts it.
+    pub queue_wait: HistogramVec,
+    pub queue_depth: IntGaugeVec,
+    pub model_load: HistogramVec,
+    /// Keys and values of the sequences being decoded.
+    pub kv_cache_bytes: IntGaugeVec,
+    /// Kv cache blocks in use by sequences, sessions and cached prefixes, shared blocks counted once.
+    pub kv_pool_used_bytes: IntGaugeVec,
+    /// Room left for kv cache blocks under `--kv-cache-mb`.
+    pub kv_pool_free_bytes: IntGaugeVec,
+    /// Keys and values kept by chat sessions between turns.
+    pub session_cache_bytes: IntGauge,
+    /// Prefills by whether the prefix cache had a longer prefix of the prompt than the request brought along.
+    pub prefix_cache_lookups: IntCounterVec,
+    /// Prompt tokens served from the prefix cache.
+    pub prefix_cache_hit_tokens: IntCounterVec,
+    pub prefix_cache_bytes: IntGaugeVec,
+    pub loaded_models: IntGauge,
+}
+
+// latencies from a few milliseconds (a decode step of a small model) to minutes (loading a 72B model)
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+fn counter(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    let counter = IntCounterVec::new(Opts::new(name, help), labels).unwrap();
+    registry.register(Box::new(counter.clone())).unwrap();
+    counter
+}
+
+fn histogram(registry: &Registry, name: &str, help: &str) -> HistogramVec {
+    let opts = HistogramOpts::new(name, help).buckets(LATENCY_BUCKETS.to_vec());
+    let histogram = HistogramVec::new(opts, &["model"]).unwrap();
+    registry.register(Box::new(histogram.clone())).unwrap();
+    histogram
+}
+
+fn gauge(registry: &Registry, name: &str, help: &str) -> IntGauge {
+    let gauge = IntGauge::new(name, help).unwrap();
+    registry.register(Box::new(gauge.clone())).unwrap();
+    gauge
+}
+
+fn gauge_vec(registry: &Registry, name: &str, help: &str) -> IntGaugeVec {
+    let gauge = IntGaugeVec::new(Opts::new(name, help), &["model"]).unwrap();
+    registry.register(Box::new(gauge.clone())).unwrap();
+    gauge
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let r = Registry::new();
+        Metrics {
+            requests: counter(&r, "llm_http_requests_total", "HTTP responses by status code", &["status"]),
+            generations: counter(&r, "llm_generations_total", "Finished generations", &["model", "finish_reason"]),
+            prompt_tokens: counter(&r, "llm_prompt_tokens_total", "Prompt tokens", &["model"]),
+            cached_tokens: counter(&r, "llm_cached_prompt_tokens_total", "Prompt tokens reused from a kv cache", &["model"]),
+            completion_tokens: counter(&r, "llm_completion_tokens_total", "Generated tokens", &["model"]),
+            time_to_first_token: histogram(&r, "llm_time_to_first_token_seconds", "Time from queueing to the first token"),
+            inter_token_latency: histogram(&r, "llm_inter_token_latency_seconds", "Time between two generated tokens"),
+            queue_wait: histogram(&r, "llm_queue_wait_seconds", "Time requests wait before the engine starts them"),
+            queue_depth: gauge_vec(&r, "llm_queue_depth", "Requests waiting for the model"),
+            model_load: histogram(&r, "llm_model_load_seconds", "Time to load the weights of a model"),
+            kv_cache_bytes: gauge_vec(&r, "llm_kv_cache_bytes", "Kv cache memory of the running sequences"),
+            kv_pool_used_bytes: gauge_vec(&r, "llm_kv_pool_used_bytes", "Memory of the kv cache blocks in use"),
+            kv_pool_free_bytes: gauge_vec(&r, "llm_kv_pool_free_bytes", "Memory left for kv cache blocks"),
+            session_cache_bytes: gauge(&r, "llm_session_kv_cache_bytes", "Kv cache memory kept by chat sessions"),
+            prefix_cache_lookups: counter(&r, "llm_prefix_cache_lookups_total", "Prefix cache lookups by prefill", &["model", "result"]),
+            prefix_cache_hit_tokens: counter(&r, "llm_prefix_cache_hit_tokens_total", "Prompt tokens reused from the prefix cache", &["model"]),
+            prefix_cache_bytes: gauge_vec(&r, "llm_prefix_cache_bytes", "Kv cache memory of the cached prompt prefixes"),
+            loaded_models: gauge(&r, "llm_loaded_models", "Models in memory"),
+            registry: r,
+        }
+    }
+
+    /// Everything in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            eprintln!("failed to encode the metrics: {}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
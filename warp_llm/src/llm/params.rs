@@ -0,0 +1,250 @@
+// Per-request sampling settings layered over the CLI defaults in `Args`
+
+use std::time::Duration;
+
+use candle_transformers::generation::{LogitsProcessor, Sampling};
+use clap::ValueEnum;
+use serde_derive::{Deserialize, Serialize};
+
+use super::error::LlmError;
+use super::llm::Args;
+
+
+/// Sampling settings an HTTP request may set; anything left out falls back to the CLI `Args`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct GenerationParams {
+    /// The temperature used to generate samples, use 0 for greedy sampling.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    /// Only sample among the top K samples.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+    /// Nucleus sampling probability cutoff.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    /// The seed to use when generating random samples.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// The maximum number of tokens to generate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+    /// Penalty to be applied for repeating tokens, 1. means no penalty.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat_penalty: Option<f32>,
+    /// The context size to consider for the repeat penalty.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat_last_n: Option<usize>,
+    /// Strings that end the generation when they show up in the output; they are not returned.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<StopSpec>,
+    /// Token ids that end the generation in addition to the end-of-turn token of the model.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_token_ids: Option<Vec<u32>>,
+    /// What to do with a prompt that does not fit into the context window.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncation: Option<Truncation>,
+    /// Seconds after which the generation ends with what it has so far.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<f64>,
+}
+
+/// How a prompt that is too long for the context window is handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Truncation {
+    /// Reject the request with `context_length_exceeded`.
+    Error,
+    /// Cut tokens from the start of the rendered prompt.
+    Left,
+    /// Leave out the oldest turns of the conversation, keeping the system prompt.
+    OldestMessages,
+}
+
+/// `stop` is either a single string or a list of strings.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum StopSpec {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl StopSpec {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            StopSpec::One(s) => vec![s],
+            StopSpec::Many(v) => v,
+        }
+    }
+}
+
+/// At most this many stop strings per request.
+pub const MAX_STOP_SEQUENCES: usize = 16;
+
+/// The longest `timeout` a request may ask for, in seconds (a day).
+pub const MAX_TIMEOUT_SECS: f64 = 86400.0;
+
+/// Fully resolved settings the engine generates with.
+#[derive(Debug, Clone)]
+pub struct GenerationConfig {
+    pub temperature: f64,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f64>,
+    pub seed: u64,
+    pub max_tokens: usize,
+    pub repeat_penalty: f32,
+    pub repeat_last_n: usize,
+    pub stop: Vec<String>,
+    pub stop_token_ids: Vec<u32>,
+    pub truncation: Truncation,
+    pub timeout: Option<Duration>,
+}
+
+impl GenerationConfig {
+    /// The CLI defaults.
+    pub fn from_args(args: &Args) -> Self {
+        GenerationConfig {
+            temperature: args.temperature,
+            top_k: args.top_k,
+            top_p: args.top_p,
+            seed: args.seed,
+            max_tokens: args.sample_len,
+            repeat_penalty: args.repeat_penalty,
+            repeat_last_n: args.repeat_last_n,
+            stop: vec![],
+            stop_token_ids: vec![],
+            truncation: args.truncation,
+            timeout: match args.request_timeout_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+        }
+    }
+
+    /// How many prompt tokens may stay when truncating: the window minus room for `max_tokens`,
+    /// but never less than half of the window.
+    pub fn prompt_budget(&self, context_length: usize) -> usize {
+        context_length - self.max_tokens.min(context_length / 2)
+    }
+
+    pub fn logits_processor(&self) -> LogitsProcessor {
+        let temperature = self.temperature;
+        let sampling = if temperature <= 0. {
+            Sampling::ArgMax
+        } else {
+            match (self.top_k, self.top_p) {
+                (None, None) => Sampling::All { temperature },
+                (Some(k), None) => Sampling::TopK { k, temperature },
+                (None, Some(p)) => Sampling::TopP { p, temperature },
+                (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+            }
+        };
+        LogitsProcessor::from_sampling(self.seed, sampling)
+    }
+}
+
+impl GenerationParams {
+    /// Fills the unset fields from `args` and checks the ranges; the error message is meant for the client.
+    pub fn resolve(&self, args: &Args) -> Result<GenerationConfig, LlmError> {
+        let defaults = GenerationConfig::from_args(args);
+        if let Some(timeout) = self.timeout
+            && !(timeout > 0.0 && timeout <= MAX_TIMEOUT_SECS)
+        {
+            return Err(LlmError::InvalidParams(format!(
+                "`timeout` must be a positive number of seconds up to {}, got {}",
+                MAX_TIMEOUT_SECS, timeout
+            )));
+        }
+        let config = GenerationConfig {
+            temperature: self.temperature.unwrap_or(defaults.temperature),
+            top_k: self.top_k.or(defaults.top_k),
+            top_p: self.top_p.or(defaults.top_p),
+            seed: self.seed.unwrap_or(defaults.seed),
+            max_tokens: self.max_tokens.unwrap_or(defaults.max_tokens),
+            repeat_penalty: self.repeat_penalty.unwrap_or(defaults.repeat_penalty),
+            repeat_last_n: self.repeat_last_n.unwrap_or(defaults.repeat_last_n),
+            stop: self.stop.clone().map(StopSpec::into_vec).unwrap_or_default(),
+            stop_token_ids: self.stop_token_ids.clone().unwrap_or_default(),
+            truncation: self.truncation.unwrap_or(defaults.truncation),
+            // the server limit still applies to requests asking for more
+            timeout: match (self.timeout.map(Duration::from_secs_f64), defaults.timeout) {
+                (Some(asked), Some(limit)) => Some(asked.min(limit)),
+                (asked, limit) => asked.or(limit),
+            },
+        };
+
+        if !(0.0..=2.0).contains(&config.temperature) {
+            return Err(LlmError::InvalidParams(format!(
+                "`temperature` must be between 0 and 2, got {}",
+                config.temperature
+            )));
+        }
+        if let Some(top_p) = config.top_p
+            && !(top_p > 0.0 && top_p <= 1.0)
+        {
+            return Err(LlmError::InvalidParams(format!("`top_p` must be in (0, 1], got {}", top_p)));
+        }
+        if config.top_k == Some(0) {
+            return Err(LlmError::InvalidParams("`top_k` must be at least 1".to_string()));
+        }
+        if config.max_tokens == 0 {
+            return Err(LlmError::InvalidParams("`max_tokens` must be at least 1".to_string()));
+        }
+        if config.max_tokens > args.max_tokens_limit {
+            return Err(LlmError::InvalidParams(format!(
+                "`max_tokens` must be at most {}, got {}",
+                args.max_tokens_limit, config.max_tokens
+            )));
+        }
+        if !(config.repeat_penalty.is_finite() && config.repeat_penalty > 0.0) {
+            return Err(LlmError::InvalidParams(format!(
+                "`repeat_penalty` must be a positive number, got {}",
+                config.repeat_penalty
+            )));
+        }
+        if config.stop.len() > MAX_STOP_SEQUENCES {
+            return Err(LlmError::InvalidParams(format!(
+                "`stop` takes at most {} strings, got {}",
+                MAX_STOP_SEQUENCES,
+                config.stop.len()
+            )));
+        }
+        if config.stop.iter().any(|s| s.is_empty()) {
+            return Err(LlmError::InvalidParams("`stop` strings must not be empty".to_string()));
+        }
+        Ok(config)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn params(timeout: f64) -> GenerationParams {
+        GenerationParams { timeout: Some(timeout), ..GenerationParams::default() }
+    }
+
+    #[test]
+    fn out_of_range_timeouts_are_rejected() {
+        for args in [Args::parse_from(["llm_v1"]), Args::parse_from(["llm_v1", "--request-timeout-secs", "0"])] {
+            for timeout in [1e20, f64::MAX, f64::INFINITY, f64::NAN, 0.0, -1.0, MAX_TIMEOUT_SECS + 1.0] {
+                assert!(
+                    matches!(params(timeout).resolve(&args), Err(LlmError::InvalidParams(_))),
+                    "timeout {timeout} accepted"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn timeouts_are_capped_by_the_server_limit() {
+        let args = Args::parse_from(["llm_v1", "--request-timeout-secs", "10"]);
+        assert_eq!(params(2.5).resolve(&args).unwrap().timeout, Some(Duration::from_secs_f64(2.5)));
+        assert_eq!(params(MAX_TIMEOUT_SECS).resolve(&args).unwrap().timeout, Some(Duration::from_secs(10)));
+        let args = Args::parse_from(["llm_v1", "--request-timeout-secs", "0"]);
+        let timeout = params(MAX_TIMEOUT_SECS).resolve(&args).unwrap().timeout;
+        assert_eq!(timeout, Some(Duration::from_secs_f64(MAX_TIMEOUT_SECS)));
+    }
+}
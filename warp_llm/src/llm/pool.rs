@@ -0,0 +1,198 @@
+// Every registered model gets its own engine (and so its own queue and batch), loaded on first
+// use and dropped again when idle or when it is the least recently used one over the limit
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use serde_derive::Serialize;
+use tokio::sync::Mutex;
+
+use super::engine::{self, EngineHandle};
+use super::error::LlmError;
+use super::llm::Args;
+use super::llm_ops;
+use super::registry::{ModelEntry, ModelRegistry};
+
+
+struct Slot {
+    // dropping the last handle stops the engine thread once its running sequences are done
+    engine: Option<EngineHandle>,
+    last_used: Instant,
+}
+
+/// Progress of the last hot swap of a model.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum SwapStatus {
+    Loading { gguf: PathBuf },
+    Done { gguf: PathBuf },
+    Failed { gguf: PathBuf, error: String },
+}
+
+pub struct ModelPool {
+    // entries change when a new version of a model is swapped in
+    registry: RwLock<ModelRegistry>,
+    /// Served when a request names no model; never unloaded for being idle.
+    default: String,
+    args: Arc<Args>,
+    slots: HashMap<String, Arc<Mutex<Slot>>>,
+    swaps: std::sync::Mutex<HashMap<String, SwapStatus>>,
+}
+
+impl ModelPool {
+    pub fn new(registry: ModelRegistry, default: String, args: Arc<Args>) -> Self {
+        let slots = registry
+            .models()
+            .iter()
+            .map(|m| (m.id.clone(), Arc::new(Mutex::new(Slot { engine: None, last_used: Instant::now() }))))
+            .collect();
+        ModelPool {
+            registry: RwLock::new(registry),
+            default,
+            args,
+            slots,
+            swaps: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn default_model(&self) -> &str {
+        &self.default
+    }
+
+    /// Checks that `id` is registered; `None` stands for the default model.
+    pub fn model_id<'a>(&'a self, id: Option<&'a str>) -> Result<&'a str, LlmError> {
+        let id = id.unwrap_or(&self.default);
+        match self.slots.contains_key(id) {
+            true => Ok(id),
+            false => Err(LlmError::UnknownModel(id.to_string())),
+        }
+    }
+
+    /// The engine of model `id` (the default one for `None`), loading the model if needed.
+    /// Concurrent requests for a model that is still loading wait for that one load.
+    pub async fn engine(&self, id: Option<&str>) -> Result<EngineHandle, LlmError> {
+        let id = self.model_id(id)?;
+        let mut slot = self.slots[id].lock().await;
+        slot.last_used = Instant::now();
+        if let Some(engine) = &slot.engine {
+            return Ok(engine.clone());
+        }
+
+        self.make_room(id);
+        println!("loading model {}", id);
+        let entry = self.entry(id).ok_or_else(|| LlmError::UnknownModel(id.to_string()))?;
+        let engine = self.load(entry).await?;
+        slot.engine = Some(engine.clone());
+        slot.last_used = Instant::now();
+        Ok(engine)
+    }
+
+    // Builds the model off the async runtime and starts its engine
+    async fn load(&self, entry: ModelEntry) -> Result<EngineHandle, LlmError> {
+        let args = self.args.clone();
+        tokio::task::spawn_blocking(move || {
+            let (model, tokenizer) = llm_ops::build_model(&entry, args.cpu)?;
+            engine::spawn(model, tokenizer, &entry, &args)
+        })
+        .await
+        .map_err(|e| LlmError::ModelLoad(e.to_string()))?
+    }
+
+    pub fn entry(&self, id: &str) -> Option<ModelEntry> {
+        self.registry.read().unwrap().get(id).cloned()
+    }
+
+    /// Loads `entry` in the background and, once it is ready, puts it in place of the running
+    /// version of the model with the same id. Requests that already got the old engine finish
+    /// on it, and its weights are dropped with the last of them.
+    /// Returns `false` when a swap of that model is still loading.
+    pub fn swap(self: &Arc<Self>, entry: ModelEntry) -> Result<bool, LlmError> {
+        let id = self.model_id(Some(&entry.id))?.to_string();
+        {
+            let mut swaps = self.swaps.lock().unwrap();
+            if let Some(SwapStatus::Loading { .. }) = swaps.get(&id) {
+                return Ok(false);
+            }
+            swaps.insert(id.clone(), SwapStatus::Loading { gguf: entry.gguf.clone() });
+        }
+        println!("swapping in {} for model {}", entry.gguf.display(), id);
+
+        let pool = self.clone();
+        tokio::spawn(async move {
+            let gguf = entry.gguf.clone();
+            let status = match pool.load(entry.clone()).await {
+                Ok(engine) => {
+                    let mut slot = pool.slots[&id].lock().await;
+                    if slot.engine.is_none() {
+                        pool.make_room(&id);
+                    }
+                    slot.engine = Some(engine);
+                    slot.last_used = Instant::now();
+                    pool.registry.write().unwrap().replace(entry);
+                    println!("model {} now runs {}", id, gguf.display());
+                    SwapStatus::Done { gguf }
+                }
+                Err(e) => {
+                    eprintln!("swapping model {} failed: {}", id, e);
+                    SwapStatus::Failed { gguf, error: e.to_string() }
+                }
+            };
+            pool.swaps.lock().unwrap().insert(id, status);
+        });
+        Ok(true)
+    }
+
+    pub fn swap_status(&self, id: &str) -> Option<SwapStatus> {
+        self.swaps.lock().unwrap().get(id).cloned()
+    }
+
+    /// Whether model `id` is in memory right now.
+    pub fn is_loaded(&self, id: &str) -> bool {
+        self.slots.get(id).and_then(|slot| slot.try_lock().ok()).is_some_and(|slot| slot.engine.is_some())
+    }
+
+    // Unloads the least recently used models so that one more fits under `--max-loaded-models`;
+    // models busy loading are skipped
+    fn make_room(&self, loading: &str) {
+        let mut loaded: Vec<_> = self
+            .slots
+            .iter()
+            .filter(|(id, _)| id.as_str() != loading)
+            .filter_map(|(id, slot)| slot.try_lock().ok().map(|slot| (id, slot)))
+            .filter(|(_, slot)| slot.engine.is_some())
+            .collect();
+        loaded.sort_by_key(|(_, slot)| slot.last_used);
+        let excess = (loaded.len() + 1).saturating_sub(self.args.max_loaded_models.max(1));
+        for (id, mut slot) in loaded.into_iter().take(excess) {
+            println!("unloading model {} to make room", id);
+            slot.engine = None;
+        }
+    }
+
+    /// Drops the models other than the default one that were not used for `idle`.
+    pub fn unload_idle(&self, idle: Duration) {
+        for (id, slot) in self.slots.iter() {
+            if *id == self.default {
+                continue;
+            }
+            if let Ok(mut slot) = slot.try_lock()
+                && slot.engine.is_some()
+                && slot.last_used.elapsed() > idle
+            {
+                println!("unloading model {} after {}s idle", id, slot.last_used.elapsed().as_secs());
+                slot.engine = None;
+            }
+        }
+    }
+
+    /// The registered models and whether each is loaded right now.
+    pub fn list(&self) -> Vec<(ModelEntry, bool)> {
+        let entries = self.registry.read().unwrap().models().to_vec();
+        entries.into_iter().map(|entry| {
+            let loaded = self.is_loaded(&entry.id);
+            (entry, loaded)
+        }).collect()
+    }
+}
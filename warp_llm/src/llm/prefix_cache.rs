@@ -0,0 +1,202 @@
+// Kv cache blocks of prompt prefixes shared across requests, e.g. a long system prompt, so that
+// prefill only runs on the part of a prompt that no earlier request saw
+
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Arc;
+
+use super::kv_cache::{BLOCK_SIZE, BlockTable, KvBlock};
+
+
+// the parent of the first block of every prompt
+const ROOT: u64 = 0;
+
+struct Block {
+    parent: u64,
+    tokens: Vec<u32>,
+    // shared with the sequences that went through these tokens, never written again: a
+    // sequence writing into a shared block copies it first
+    block: Arc<KvBlock>,
+    last_used: u64,
+}
+
+/// The cached blocks of one engine, keyed by the hash of all the tokens up to and including
+/// them, so that a block is shared by every prompt starting with the same tokens and a lookup
+/// walks the blocks of a prompt like a trie.
+///
+/// Blocks are only used together with all the blocks before them, and a lookup marks them as
+/// used from the last one to the first, so every block was used more recently than the blocks
+/// after it: evicting the least recently used block never leaves a block without its prefix.
+pub struct PrefixCache {
+    blocks: HashMap<u64, Block>,
+    // (last_used, hash) of every block, least recently used first
+    lru: BTreeSet<(u64, u64)>,
+    clock: u64,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+fn block_hash(parent: u64, tokens: &[u32]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    parent.hash(&mut hasher);
+    tokens.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl PrefixCache {
+    pub fn new(max_bytes: usize) -> Self {
+        PrefixCache {
+            blocks: HashMap::new(),
+            lru: BTreeSet::new(),
+            clock: 0,
+            bytes: 0,
+            max_bytes,
+        }
+    }
+
+    /// The memory of all cached blocks, some of which running sequences use as well.
+    pub fn size_in_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    // The hashes of the cached blocks `tokens` starts with
+    fn matching_blocks(&self, tokens: &[u32]) -> Vec<u64> {
+        let mut chain = Vec::new();
+        let mut parent = ROOT;
+        for block in tokens.chunks_exact(BLOCK_SIZE) {
+            let hash = block_hash(parent, block);
+            match self.blocks.get(&hash) {
+                // the tokens rule out hash collisions
+                Some(cached) if cached.parent == parent && cached.tokens == block => chain.push(hash),
+                _ => break,
+            }
+            parent = hash;
+        }
+        chain
+    }
+
+    /// The number of leading tokens of `tokens` whose keys and values are cached, a multiple of
+    /// `BLOCK_SIZE`.
+    pub fn longest_prefix(&self, tokens: &[u32]) -> usize {
+        self.matching_blocks(tokens).len() * BLOCK_SIZE
+    }
+
+    /// A block table sharing the cached blocks of the `longest_prefix` of `tokens`.
+    pub fn restore(&mut self, tokens: &[u32]) -> BlockTable {
+        let chain = self.matching_blocks(tokens);
+        self.touch(&chain);
+        BlockTable::from_blocks(chain.iter().map(|hash| self.blocks[hash].block.clone()).collect())
+    }
+
+    /// Caches the full blocks of `tokens` that are not cached yet, sharing them with `kv`, the
+    /// block table of a sequence that went through them.
+    pub fn insert(&mut self, tokens: &[u32], kv: &BlockTable) {
+        let mut chain = self.matching_blocks(tokens);
+        let mut parent = chain.last().copied().unwrap_or(ROOT);
+        for (i, block) in tokens.chunks_exact(BLOCK_SIZE).enumerate().skip(chain.len()) {
+            let hash = block_hash(parent, block);
+            // only a hash collision, as the block did not match
+            if self.blocks.contains_key(&hash) {
+                break;
+            }
+            let Some(kv_block) = kv.full_block(i) else {
+                break;
+            };
+            if kv_block.size_in_bytes() > self.max_bytes {
+                break;
+            }
+            self.bytes += kv_block.size_in_bytes();
+            let block = Block { parent, tokens: block.to_vec(), block: kv_block.clone(), last_used: 0 };
+            self.lru.insert((0, hash));
+            self.blocks.insert(hash, block);
+            chain.push(hash);
+            parent = hash;
+        }
+        self.touch(&chain);
+        while self.bytes > self.max_bytes && self.evict_one() {}
+    }
+
+    // Marks the blocks of a chain as used, the first one last
+    fn touch(&mut self, chain: &[u64]) {
+        for hash in chain.iter().rev() {
+            if let Some(block) = self.blocks.get_mut(hash) {
+                self.clock += 1;
+                self.lru.remove(&(block.last_used, *hash));
+                block.last_used = self.clock;
+                self.lru.insert((self.clock, *hash));
+            }
+        }
+    }
+
+    // Drops the least recently used block. `false` when the cache is empty.
+    fn evict_one(&mut self) -> bool {
+        let Some(&(last_used, hash)) = self.lru.first() else {
+            return false;
+        };
+        self.evict(last_used, hash);
+        true
+    }
+
+    /// Drops the least recently used block no sequence or session holds, giving its memory back
+    /// to the pool. `false` when every cached block is in use elsewhere.
+    ///
+    /// A sequence holds the blocks before the ones it holds as well, so this is never a block
+    /// whose later blocks stay cached without it, short of the window dropping them.
+    pub fn evict_unshared(&mut self) -> bool {
+        let unshared = self.lru.iter().find(|(_, hash)| Arc::strong_count(&self.blocks[hash].block) == 1);
+        let Some(&(last_used, hash)) = unshared else {
+            return false;
+        };
+        self.evict(last_used, hash);
+        true
+    }
+
+    fn evict(&mut self, last_used: u64, hash: u64) {
+        self.lru.remove(&(last_used, hash));
+        if let Some(block) = self.blocks.remove(&hash) {
+            self.bytes -= block.block.size_in_bytes();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use candle_core::Device;
+
+    use super::*;
+    use crate::llm::kv_cache::BlockAllocator;
+
+    fn prefill(tokens: &[u32], allocator: &BlockAllocator) -> BlockTable {
+        let mut table = BlockTable::default();
+        table.step(tokens.len(), None, allocator, &Device::Cpu).unwrap();
+        table
+    }
+
+    #[test]
+    fn evict_unshared_skips_blocks_a_sequence_holds() {
+        let allocator = BlockAllocator::new(8, 8);
+        let mut cache = PrefixCache::new(usize::MAX);
+        let held: Vec<u32> = (0..2 * BLOCK_SIZE as u32).collect();
+        let dropped: Vec<u32> = (100..100 + 2 * BLOCK_SIZE as u32).collect();
+        // the held prompt is the least recently used one
+        let table = prefill(&held, &allocator);
+        cache.insert(&held, &table);
+        cache.insert(&dropped, &prefill(&dropped, &allocator));
+        assert_eq!(allocator.used(), 4);
+
+        assert!(cache.evict_unshared());
+        assert!(cache.evict_unshared());
+        assert!(!cache.evict_unshared());
+        assert_eq!(cache.longest_prefix(&dropped), 0);
+        assert_eq!(cache.longest_prefix(&held), 2 * BLOCK_SIZE);
+        assert_eq!(allocator.used(), 2);
+        assert_eq!(cache.size_in_bytes(), 16);
+
+        drop(table);
+        assert!(cache.evict_unshared());
+        assert!(cache.evict_unshared());
+        assert_eq!(allocator.used(), 0);
+        assert_eq!(cache.size_in_bytes(), 0);
+    }
+}
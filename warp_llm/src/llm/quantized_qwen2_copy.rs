@@ -28,6 +28,8 @@ use candle_core::{
 use candle_nn::{Embedding, Module};
 use std::collections::HashMap;
 
+use super::kv_cache::{BlockAllocator, BlockTable, KvCacheDtype, KvStep, LayerPool, BLOCK_SIZE};
+
 #[derive(Debug, Clone)]
 struct Mlp {
     feed_forward_w1: QMatMul,
@@ -44,7 +46,7 @@ impl Module for Mlp {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct LayerWeights {
     attention_wq: QMatMul,
     attention_wk: QMatMul,
@@ -62,7 +64,7 @@ pub struct LayerWeights {
     cos: Tensor,
     sin: Tensor,
     neg_inf: Tensor,
-    pub kv_cache: Option<(Tensor, Tensor)>,
+    kv_pool: LayerPool,
     span_attn: tracing::Span,
     span_rot: tracing::Span,
     span_mlp: tracing::Span,
@@ -83,14 +85,9 @@ impl LayerWeights {
         candle_nn::rotary_emb::rope(&x.contiguous()?, &cos, &sin)
     }
 
-    fn forward_attn(
-        &mut self,
-        x: &Tensor,
-        mask: Option<&Tensor>,
-        index_pos: usize,
-    ) -> Result<Tensor> {
-        let _enter = self.span_attn.enter();
-        let (b_sz, seq_len, n_embd) = x.dims3()?;
+    // q, k, v of shape (b_sz, n_head | n_kv_head, seq_len, head_dim), before the rotary embedding
+    fn project_qkv(&self, x: &Tensor) -> Result<(Tensor, Tensor, Tensor)> {
+        let (b_sz, seq_len, _n_embd) = x.dims3()?;
 
         let q = self.attention_wq.forward(x)?;
         let k = self.attention_wk.forward(x)?;
@@ -112,27 +109,21 @@ impl LayerWeights {
             .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
             .transpose(1, 2)?
             .contiguous()?;
+        Ok((q, k, v))
+    }
 
-        // let (q, k) = self
-        //     .rotary_embedding
-        //     .apply_rotary_emb_qkv(&q, &k, index_pos)?;
-        let q = self.apply_rotary_emb(&q, index_pos)?;
-        let k = self.apply_rotary_emb(&k, index_pos)?;
-
-        let (k, v) = match &self.kv_cache {
-            None => (k, v),
-            Some((k_cache, v_cache)) => {
-                if index_pos == 0 {
-                    (k, v)
-                } else {
-                    let k = Tensor::cat(&[k_cache, &k], 2)?;
-                    let v = Tensor::cat(&[v_cache, &v], 2)?;
-                    (k, v)
-                }
-            }
-        };
-        self.kv_cache = Some((k.clone(), v.clone()));
-
+    // softmax(q k^T / sqrt(d)) v over the full (cached + new) keys and values
+    fn attend(&self, q: &Tensor, k: Tensor, v: Tensor, mask: Option<&Tensor>) -> Result<Tensor> {
+        // Without a mask (decode steps) the query heads sharing a kv head are folded into the rows
+        // of one matmul, so the cached keys and values are read in place instead of being copied
+        // for every head.
+        if mask.is_none() {
+            let (b_sz, n_head, seq_len, head_dim) = q.dims4()?;
+            let q = q.reshape((b_sz, self.n_kv_head, n_head / self.n_kv_head * seq_len, head_dim))?;
+            let att = (q.matmul(&k.t()?)? / (head_dim as f64).sqrt())?;
+            let att = candle_nn::ops::softmax_last_dim(&att)?;
+            return att.matmul(&v)?.reshape((b_sz, n_head, seq_len, head_dim));
+        }
         // Support for MQA, useful for 70B models and mistral.
         let k = repeat_kv(k, self.n_head / self.n_kv_head)?;
         let v = repeat_kv(v, self.n_head / self.n_kv_head)?;
@@ -147,11 +138,65 @@ impl LayerWeights {
         };
         let att = candle_nn::ops::softmax_last_dim(&att)?;
         // Convert to contiguous as matmul doesn't support strided vs for now.
-        let y = att.matmul(&v.contiguous()?)?;
+        att.matmul(&v.contiguous()?)
+    }
+
+    fn forward_attn(
+        &mut self,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        index_pos: usize,
+        kv: &KvStep,
+    ) -> Result<Tensor> {
+        let _enter = self.span_attn.enter();
+        let (b_sz, seq_len, n_embd) = x.dims3()?;
+
+        let (q, k, v) = self.project_qkv(x)?;
+
+        // let (q, k) = self
+        //     .rotary_embedding
+        //     .apply_rotary_emb_qkv(&q, &k, index_pos)?;
+        let q = self.apply_rotary_emb(&q, index_pos)?;
+        let k = self.apply_rotary_emb(&k, index_pos)?;
+
+        let (k, v) = self.kv_pool.write_and_gather(kv, &k, &v)?;
+
+        let y = self.attend(&q, k, v, mask)?;
         let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, n_embd])?;
         let y = self.attention_wo.forward(&y)?;
         Ok(y)
     }
+
+    // One decode step of `b_sz` independent sequences, x of shape (b_sz, 1, n_embd).
+    // The projections run batched; attention runs per sequence against its own blocks.
+    fn forward_attn_batch(&mut self, x: &Tensor, cos: &Tensor, sin: &Tensor, kvs: &[KvStep]) -> Result<Tensor> {
+        let _enter = self.span_attn.enter();
+        let (b_sz, _seq_len, n_embd) = x.dims3()?;
+
+        let (q, k, v) = self.project_qkv(x)?;
+
+        // every sequence sits at its own position: lay the batch out along the sequence axis,
+        // so that rope applies row i of the gathered cos / sin to sequence i
+        let rope = |x: &Tensor| -> Result<Tensor> {
+            let _enter = self.span_rot.enter();
+            let x = x.transpose(0, 2)?.contiguous()?;
+            candle_nn::rotary_emb::rope(&x, cos, sin)?.transpose(0, 2)
+        };
+        let q = rope(&q)?;
+        let k = rope(&k)?;
+
+        let mut ys = Vec::with_capacity(b_sz);
+        for (i, kv) in kvs.iter().enumerate() {
+            let q = q.narrow(0, i, 1)?.contiguous()?;
+            let k = k.narrow(0, i, 1)?;
+            let v = v.narrow(0, i, 1)?;
+            let (k, v) = self.kv_pool.write_and_gather(kv, &k, &v)?;
+            ys.push(self.attend(&q, k, v, None)?);
+        }
+        let y = Tensor::cat(&ys, 0)?;
+        let y = y.transpose(1, 2)?.reshape(&[b_sz, 1, n_embd])?;
+        self.attention_wo.forward(&y)
+    }
 }
 
 pub struct ModelWeights {
@@ -160,6 +205,10 @@ pub struct ModelWeights {
     norm: RmsNorm,
     output: QMatMul,
     masks: HashMap<usize, Tensor>,
+    context_length: usize,
+    // keep the keys and values of only this many positions per sequence
+    kv_window: Option<usize>,
+    allocator: BlockAllocator,
     span: tracing::Span,
     span_output: tracing::Span,
 }
@@ -184,6 +233,11 @@ fn precomput_freqs_cis(
     Ok((cos, sin))
 }
 
+// The memory of one kv cache block: keys and values of every layer
+fn kv_block_bytes(layers: &[LayerWeights], dtype: KvCacheDtype) -> usize {
+    layers.iter().map(|layer| 2 * layer.n_kv_head * BLOCK_SIZE * dtype.head_bytes(layer.head_dim)).sum()
+}
+
 impl ModelWeights {
     pub fn from_gguf<R: std::io::Seek + std::io::Read>(
         ct: gguf_file::Content,
@@ -279,13 +333,14 @@ impl ModelWeights {
                 n_kv_head: head_count_kv,
                 head_dim,
                 neg_inf: neg_inf.clone(),
-                kv_cache: None,
+                kv_pool: LayerPool::new(head_count_kv, head_dim, KvCacheDtype::F32, device),
                 span_attn,
                 span_rot,
                 span_mlp,
             });
         }
 
+        let allocator = BlockAllocator::new(usize::MAX, kv_block_bytes(&layers, KvCacheDtype::F32));
         let span = tracing::span!(tracing::Level::TRACE, "model");
         let span_output = tracing::span!(tracing::Level::TRACE, "output");
 
@@ -295,38 +350,92 @@ impl ModelWeights {
             norm,
             output,
             masks: HashMap::new(),
+            context_length,
+            kv_window: None,
+            allocator,
             span,
             span_output,
         })
     }
 
-    fn mask(&mut self, t: usize, device: &Device) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
+    /// Number of positions the rotary tables cover (`qwen2.context_length`), prompt and generated tokens together.
+    pub fn context_length(&self) -> usize {
+        self.context_length
+    }
+
+    /// Limits the kv cache of every sequence to its last `window` positions, turning it into a
+    /// ring buffer; attention then looks back at most `window` positions.
+    pub fn set_kv_window(&mut self, window: Option<usize>) {
+        self.kv_window = window;
+    }
+
+    /// Causal mask for `t` new tokens following `index_pos` cached ones, shape `(t, index_pos + t)`.
+    fn mask(&mut self, t: usize, index_pos: usize, device: &Device) -> Result<Tensor> {
+        if let Some(mask) = self.masks.get(&t).filter(|_| index_pos == 0) {
             Ok(mask.clone())
         } else {
             let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
+                .flat_map(|i| (0..index_pos + t).map(move |j| u8::from(j > i + index_pos)))
                 .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), device)?;
-            self.masks.insert(t, mask.clone());
+            let mask = Tensor::from_slice(&mask, (t, index_pos + t), device)?;
+            // only the prefill masks are reused, the offset ones vary with every turn
+            if index_pos == 0 {
+                self.masks.insert(t, mask.clone());
+            }
             Ok(mask)
         }
     }
 
-    pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+    /// Caps the kv cache of all sequences together at `max_bytes`, i.e. the blocks of the pool,
+    /// and stores it as `dtype`. Call it before running the model: the pools start over empty.
+    pub fn set_kv_cache(&mut self, max_bytes: usize, dtype: KvCacheDtype) -> Result<()> {
+        let block_bytes = kv_block_bytes(&self.layers, dtype);
+        if max_bytes < block_bytes {
+            candle_core::bail!("the kv cache needs room for at least one block of {block_bytes} bytes, got {max_bytes}");
+        }
+        self.allocator = BlockAllocator::new(max_bytes / block_bytes, block_bytes);
+        for layer in self.layers.iter_mut() {
+            let device = layer.neg_inf.device().clone();
+            layer.kv_pool = LayerPool::new(layer.n_kv_head, layer.head_dim, dtype, &device);
+        }
+        Ok(())
+    }
+
+    /// Hands out the blocks of the kv cache, e.g. to reserve them before a forward pass.
+    pub fn block_allocator(&self) -> &BlockAllocator {
+        &self.allocator
+    }
+
+    /// Grows the pools of the kv cache to hold every block handed out, a chunk at a time.
+    /// A forward pass fails on blocks the pools do not hold, so reserve the blocks of a pass
+    /// and call this before running it.
+    pub fn grow_kv_cache(&mut self) -> Result<()> {
+        for layer in self.layers.iter_mut() {
+            layer.kv_pool.grow(&self.allocator)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the tokens `x` (batch size 1) on top of the positions in `kv`, which are extended by
+    /// them, and returns the logits of the last one.
+    pub fn forward(&mut self, x: &Tensor, kv: &mut BlockTable) -> Result<Tensor> {
         let (_b_sz, seq_len) = x.dims2()?;
+        let index_pos = kv.len();
+        // a window only keeps the keys and values of its last positions to attend to
+        let cached = self.kv_window.map_or(index_pos, |window| index_pos.min(window));
         let mask = if seq_len == 1 {
             None
         } else {
-            Some(self.mask(seq_len, x.device())?)
+            Some(self.mask(seq_len, cached, x.device())?)
         };
+        let step = kv.step(seq_len, self.kv_window, &self.allocator, x.device())?;
         let _enter = self.span.enter();
         let mut layer_in = self.tok_embeddings.forward(x)?;
         for layer in self.layers.iter_mut() {
             let x = layer_in;
             let residual = &x;
             let x = layer.attention_norm.forward(&x)?;
-            let attn = layer.forward_attn(&x, mask.as_ref(), index_pos)?;
+            let attn = layer.forward_attn(&x, mask.as_ref(), index_pos, &step)?;
             let x = (attn + residual)?;
 
             // MLP
@@ -342,5 +451,148 @@ impl ModelWeights {
         let _enter = self.span_output.enter();
         self.output.forward(&x)
     }
+
+    /// One decode step for several independent sequences: `tokens[i]` is fed on top of the
+    /// positions in `kvs[i]`, which is extended by it. The matmuls against the weights are
+    /// shared by the whole batch. Returns the logits of shape (b_sz, vocab).
+    pub fn forward_batch(&mut self, tokens: &[u32], kvs: &mut [&mut BlockTable]) -> Result<Tensor> {
+        let b_sz = tokens.len();
+        let device = self.tok_embeddings.embeddings().device().clone();
+        let x = Tensor::new(tokens, &device)?.unsqueeze(1)?;
+        let positions = Tensor::new(kvs.iter().map(|kv| kv.len() as u32).collect::<Vec<_>>(), &device)?;
+        let steps = kvs
+            .iter_mut()
+            .map(|kv| kv.step(1, self.kv_window, &self.allocator, &device))
+            .collect::<Result<Vec<_>>>()?;
+        let _enter = self.span.enter();
+        let mut layer_in = self.tok_embeddings.forward(&x)?;
+        for layer in self.layers.iter_mut() {
+            let cos = layer.cos.index_select(&positions, 0)?;
+            let sin = layer.sin.index_select(&positions, 0)?;
+
+            let x = layer_in;
+            let residual = &x;
+            let x = layer.attention_norm.forward(&x)?;
+            let attn = layer.forward_attn_batch(&x, &cos, &sin, &steps)?;
+            let x = (attn + residual)?;
+
+            // MLP
+            let _enter = layer.span_mlp.enter();
+            let residual = &x;
+            let x = layer.ffn_norm.forward(&x)?;
+            let x = layer.mlp.forward(&x)?;
+            let x = (x + residual)?;
+            layer_in = x
+        }
+        let x = self.norm.forward(&layer_in)?;
+        let x = x.i((.., 0, ..))?;
+        let _enter = self.span_output.enter();
+        self.output.forward(&x)?.reshape((b_sz, ()))
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::quantized::{GgmlDType, QTensor};
+    use candle_core::D;
+
+    const VOCAB: usize = 64;
+    const HIDDEN: usize = 64;
+    const HEADS: u32 = 4;
+    const KV_HEADS: u32 = 2;
+    const FFN: usize = 128;
+    const BLOCKS: u32 = 2;
+
+    // A qwen2 model with random f32 weights, written to a GGUF in memory and loaded back
+    fn synthetic_model() -> ModelWeights {
+        // a fixed LCG, so that every run checks the same weights
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut random = |shape: &[usize]| {
+            let values: Vec<f32> = (0..shape.iter().product())
+                .map(|_| {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                    ((state >> 40) as f32 / (1u64 << 24) as f32 - 0.5) * 0.5
+                })
+                .collect();
+            let tensor = Tensor::from_vec(values, shape, &Device::Cpu).unwrap();
+            QTensor::quantize(&tensor, GgmlDType::F32).unwrap()
+        };
+        let head_dim = HIDDEN / HEADS as usize;
+        let kv_dim = KV_HEADS as usize * head_dim;
+        let mut tensors = vec![
+            ("token_embd.weight".to_string(), random(&[VOCAB, HIDDEN])),
+            ("output_norm.weight".to_string(), random(&[HIDDEN])),
+        ];
+        for block in 0..BLOCKS {
+            let shapes: [(&str, &[usize]); 12] = [
+                ("attn_q.weight", &[HIDDEN, HIDDEN]),
+                ("attn_k.weight", &[kv_dim, HIDDEN]),
+                ("attn_v.weight", &[kv_dim, HIDDEN]),
+                ("attn_q.bias", &[HIDDEN]),
+                ("attn_k.bias", &[kv_dim]),
+                ("attn_v.bias", &[kv_dim]),
+                ("attn_output.weight", &[HIDDEN, HIDDEN]),
+                ("ffn_gate.weight", &[FFN, HIDDEN]),
+                ("ffn_up.weight", &[FFN, HIDDEN]),
+                ("ffn_down.weight", &[HIDDEN, FFN]),
+                ("attn_norm.weight", &[HIDDEN]),
+                ("ffn_norm.weight", &[HIDDEN]),
+            ];
+            for (name, shape) in shapes {
+                tensors.push((format!("blk.{block}.{name}"), random(shape)));
+            }
+        }
+        let metadata = [
+            ("general.architecture", gguf_file::Value::String("qwen2".to_string())),
+            ("qwen2.attention.head_count", gguf_file::Value::U32(HEADS)),
+            ("qwen2.attention.head_count_kv", gguf_file::Value::U32(KV_HEADS)),
+            ("qwen2.embedding_length", gguf_file::Value::U32(HIDDEN as u32)),
+            ("qwen2.context_length", gguf_file::Value::U32(256)),
+            ("qwen2.block_count", gguf_file::Value::U32(BLOCKS)),
+            ("qwen2.attention.layer_norm_rms_epsilon", gguf_file::Value::F32(1e-6)),
+            ("qwen2.rope.freq_base", gguf_file::Value::F32(10000.)),
+        ];
+        let metadata: Vec<_> = metadata.iter().map(|(key, value)| (*key, value)).collect();
+        let tensors: Vec<_> = tensors.iter().map(|(name, tensor)| (name.as_str(), tensor)).collect();
+        let mut gguf = std::io::Cursor::new(Vec::new());
+        gguf_file::write(&mut gguf, &metadata, &tensors).unwrap();
+        gguf.set_position(0);
+        let content = gguf_file::Content::read(&mut gguf).unwrap();
+        ModelWeights::from_gguf(content, &mut gguf, &Device::Cpu).unwrap()
+    }
+
+    // The logits of the prompt and of every decode step after it, fed the same tokens
+    fn logits(dtype: KvCacheDtype, prompt: &[u32], decoded: &[u32]) -> Vec<Tensor> {
+        let mut model = synthetic_model();
+        model.set_kv_cache(64 * 1024 * 1024, dtype).unwrap();
+        let mut kv = BlockTable::default();
+        let reserve = |model: &mut ModelWeights, kv: &mut BlockTable, t: usize| {
+            assert!(kv.reserve(kv.len() + t, model.block_allocator()));
+            model.grow_kv_cache().unwrap();
+        };
+        let input = Tensor::new(prompt, &Device::Cpu).unwrap().unsqueeze(0).unwrap();
+        reserve(&mut model, &mut kv, prompt.len());
+        let mut logits = vec![model.forward(&input, &mut kv).unwrap().squeeze(0).unwrap()];
+        for &token in decoded {
+            reserve(&mut model, &mut kv, 1);
+            logits.push(model.forward_batch(&[token], &mut [&mut kv]).unwrap().get(0).unwrap());
+        }
+        logits
+    }
+
+    #[test]
+    fn int8_kv_cache_logits_stay_close_to_f32() {
+        let prompt: Vec<u32> = (0..40).map(|i| (i * 7 + 3) % VOCAB as u32).collect();
+        let decoded: Vec<u32> = (0..24).map(|i| (i * 5 + 1) % VOCAB as u32).collect();
+        let f32_logits = logits(KvCacheDtype::F32, &prompt, &decoded);
+        let int8_logits = logits(KvCacheDtype::Int8, &prompt, &decoded);
+        for (step, (a, b)) in f32_logits.iter().zip(int8_logits.iter()).enumerate() {
+            let diff = (a - b).unwrap().abs().unwrap().max(D::Minus1).unwrap().to_scalar::<f32>().unwrap();
+            let scale = a.abs().unwrap().max(D::Minus1).unwrap().to_scalar::<f32>().unwrap();
+            // int8 rounds every key and value by at most half a step of its scale, 1 / 254 of
+            // its largest entry; through two layers that stays well within 1% of the logits
+            assert!(diff <= 0.01 * scale, "step {step}: logits differ by {diff}, largest logit {scale}");
+        }
+    }
+}
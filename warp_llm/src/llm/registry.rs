@@ -0,0 +1,124 @@
+// The models this server can load, read from a registry file so nothing is looked up on the network
+
+use std::path::{Path, PathBuf};
+
+use serde_derive::{Deserialize, Serialize};
+
+use super::chat::ChatTemplate;
+use super::error::LlmError;
+use super::llm::Args;
+
+
+/// One loadable model, e.g.
+///
+/// ```toml
+/// [[models]]
+/// id = "qwen2.5-7b"
+/// gguf = "/models/qwen2.5-7b-instruct-q4_0.gguf"
+/// tokenizer = "/models/qwen2.5-7b-instruct/tokenizer.json"
+/// template = "chatml"
+/// ```
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ModelEntry {
+    /// The name clients pass as `model`.
+    pub id: String,
+    pub gguf: PathBuf,
+    pub tokenizer: PathBuf,
+    /// `chatml` (Qwen) or `deepseek` (DeepSeek-R1 distills).
+    #[serde(default)]
+    pub template: ChatTemplate,
+    /// The token ending an assistant turn; defaults to the one of `template`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eos_token: Option<String>,
+}
+
+impl ModelEntry {
+    pub fn eos_token(&self) -> &str {
+        self.eos_token.as_deref().unwrap_or(self.template.eos_token())
+    }
+}
+
+#[derive(Deserialize)]
+struct RegistryFile {
+    default: Option<String>,
+    models: Vec<ModelEntry>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelRegistry {
+    models: Vec<ModelEntry>,
+    default: String,
+}
+
+impl ModelRegistry {
+    /// The registry given with `--registry`, or else a single entry built from `--model`,
+    /// `--tokenizer` and `--which` (downloading from the Hugging Face hub what is not given).
+    pub fn from_args(args: &Args) -> Result<Self, LlmError> {
+        match &args.registry {
+            Some(path) => Self::load(Path::new(path)),
+            None => {
+                let load_error = |e: &dyn std::fmt::Display| LlmError::ModelLoad(e.to_string());
+                let entry = ModelEntry {
+                    id: args.which.name(),
+                    gguf: args.model().map_err(|e| load_error(&e))?,
+                    tokenizer: args.tokenizer_path().map_err(|e| load_error(&e))?,
+                    template: ChatTemplate::for_model(args.which),
+                    eos_token: None,
+                };
+                Ok(ModelRegistry { default: entry.id.clone(), models: vec![entry] })
+            }
+        }
+    }
+
+    /// Reads a `.toml` or `.json` registry; relative paths are taken relative to the file.
+    pub fn load(path: &Path) -> Result<Self, LlmError> {
+        let load_error = |e: &dyn std::fmt::Display| LlmError::ModelLoad(format!("{}: {}", path.display(), e));
+        let text = std::fs::read_to_string(path).map_err(|e| load_error(&e))?;
+        let file: RegistryFile = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str(&text).map_err(|e| load_error(&e))?,
+            _ => toml::from_str(&text).map_err(|e| load_error(&e))?,
+        };
+
+        let base = path.parent().unwrap_or(Path::new("."));
+        let mut models = file.models;
+        for (i, entry) in models.iter().enumerate() {
+            if models[..i].iter().any(|m| m.id == entry.id) {
+                return Err(load_error(&format!("model id `{}` is listed twice", entry.id)));
+            }
+        }
+        for entry in models.iter_mut() {
+            entry.gguf = base.join(&entry.gguf);
+            entry.tokenizer = base.join(&entry.tokenizer);
+        }
+        let default = match file.default {
+            Some(id) if models.iter().any(|m| m.id == id) => id,
+            Some(id) => return Err(load_error(&format!("the default model `{}` is not listed", id))),
+            None => match models.first() {
+                Some(entry) => entry.id.clone(),
+                None => return Err(load_error(&"no models are listed")),
+            },
+        };
+        Ok(ModelRegistry { models, default })
+    }
+
+    pub fn models(&self) -> &[ModelEntry] {
+        &self.models
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ModelEntry> {
+        self.models.iter().find(|m| m.id == id)
+    }
+
+    /// Replaces the entry with the same id, e.g. after swapping in a new quantization.
+    pub fn replace(&mut self, entry: ModelEntry) {
+        if let Some(m) = self.models.iter_mut().find(|m| m.id == entry.id) {
+            *m = entry;
+        }
+    }
+
+    /// The entry picked with `--model-id`, or the registry's default.
+    pub fn select(&self, id: Option<&str>) -> Result<&ModelEntry, LlmError> {
+        let id = id.unwrap_or(&self.default);
+        self.get(id).ok_or_else(|| LlmError::UnknownModel(id.to_string()))
+    }
+}
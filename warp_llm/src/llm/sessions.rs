@@ -0,0 +1,222 @@
+// Server-side chat sessions that keep their kv cache between turns
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::chat::ChatMessage;
+use super::kv_cache::BlockTable;
+use super::llm::random_id;
+
+
+/// The kv cache blocks of a sequence together with the tokens they were computed for.
+#[derive(Debug, Clone, Default)]
+pub struct KvSnapshot {
+    pub tokens: Vec<u32>,
+    pub blocks: BlockTable,
+    /// The engine (and so the weights) that computed them, 0 for none; other engines start over.
+    pub engine_id: u64,
+}
+
+impl KvSnapshot {
+    pub fn size_in_bytes(&self) -> usize {
+        self.blocks.size_in_bytes()
+    }
+}
+
+pub struct Session {
+    /// The name of the key that created the session, `None` without keys; other keys do not see it.
+    pub owner: Option<String>,
+    /// The model the conversation runs on; its kv cache is only valid for that model.
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    pub kv: KvSnapshot,
+    pub created: Instant,
+    pub last_used: Instant,
+    // a turn is being generated; the kv snapshot is checked out to the model meanwhile
+    busy: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SessionError {
+    NotFound,
+    Busy,
+}
+
+/// Limits of the `SessionStore`.
+#[derive(Debug, Clone)]
+pub struct SessionLimits {
+    /// Sessions idle for longer than this are deleted.
+    pub ttl: Duration,
+    /// Beyond this many sessions the least recently used one is deleted.
+    pub max_sessions: usize,
+    /// Total bytes of kv cache kept across sessions; beyond it the least recently used sessions
+    /// lose their cache (not their messages) and re-process the conversation on their next turn.
+    pub max_cache_bytes: usize,
+}
+
+pub struct SessionStore {
+    sessions: HashMap<String, Session>,
+    limits: SessionLimits,
+}
+
+impl SessionStore {
+    pub fn new(limits: SessionLimits) -> Self {
+        SessionStore { sessions: HashMap::new(), limits }
+    }
+
+    /// Starts a session of `owner` on `model` with the given opening messages (e.g. a system prompt)
+    /// and returns its id.
+    pub fn create(&mut self, owner: Option<&str>, model: String, messages: Vec<ChatMessage>) -> String {
+        let id = random_id("sess-");
+        let now = Instant::now();
+        self.sessions.insert(id.clone(), Session {
+            owner: owner.map(str::to_string),
+            model,
+            messages,
+            kv: KvSnapshot::default(),
+            created: now,
+            last_used: now,
+            busy: false,
+        });
+        self.evict();
+        id
+    }
+
+    /// Session `id` if it belongs to `owner`; sessions of other keys are not found.
+    pub fn get(&mut self, id: &str, owner: Option<&str>) -> Option<&Session> {
+        self.evict();
+        self.sessions.get(id).filter(|s| s.owner.as_deref() == owner)
+    }
+
+    fn get_mut(&mut self, id: &str, owner: Option<&str>) -> Result<&mut Session, SessionError> {
+        self.evict();
+        self.sessions.get_mut(id).filter(|s| s.owner.as_deref() == owner).ok_or(SessionError::NotFound)
+    }
+
+    /// Starts a session continuing the conversation of session `id`, whose kv cache it shares
+    /// (copy-on-write), and returns its id.
+    pub fn fork(&mut self, id: &str, owner: Option<&str>) -> Result<String, SessionError> {
+        let session = self.get_mut(id, owner)?;
+        // the kv cache is checked out to the model meanwhile
+        if session.busy {
+            return Err(SessionError::Busy);
+        }
+        let now = Instant::now();
+        let fork = Session {
+            owner: session.owner.clone(),
+            model: session.model.clone(),
+            messages: session.messages.clone(),
+            kv: session.kv.clone(),
+            created: now,
+            last_used: now,
+            busy: false,
+        };
+        let fork_id = random_id("sess-");
+        self.sessions.insert(fork_id.clone(), fork);
+        self.evict();
+        Ok(fork_id)
+    }
+
+    pub fn remove(&mut self, id: &str, owner: Option<&str>) -> bool {
+        self.get_mut(id, owner).is_ok() && self.sessions.remove(id).is_some()
+    }
+
+    /// Hands out the model, conversation and kv cache of a session for the next turn.
+    /// Until `end_turn` is called the session rejects concurrent turns.
+    pub fn begin_turn(
+        &mut self,
+        id: &str,
+        owner: Option<&str>,
+    ) -> Result<(String, Vec<ChatMessage>, KvSnapshot), SessionError> {
+        let session = self.get_mut(id, owner)?;
+        if session.busy {
+            return Err(SessionError::Busy);
+        }
+        session.busy = true;
+        session.last_used = Instant::now();
+        Ok((session.model.clone(), session.messages.clone(), std::mem::take(&mut session.kv)))
+    }
+
+    /// Stores the result of a turn. The session may have been deleted in the meantime,
+    /// in which case the result is dropped.
+    pub fn end_turn(&mut self, id: &str, messages: Vec<ChatMessage>, kv: KvSnapshot) {
+        if let Some(session) = self.sessions.get_mut(id) {
+            session.messages = messages;
+            session.kv = kv;
+            session.busy = false;
+            session.last_used = Instant::now();
+        }
+        self.evict();
+    }
+
+    pub fn cache_bytes(&self) -> usize {
+        self.sessions.values().map(|s| s.kv.size_in_bytes()).sum()
+    }
+
+    // TTL first, then the session count, then the memory cap, always least recently used first
+    fn evict(&mut self) {
+        let now = Instant::now();
+        let ttl = self.limits.ttl;
+        self.sessions.retain(|_, s| s.busy || now.duration_since(s.last_used) <= ttl);
+
+        let mut idle: Vec<(Instant, String)> = self
+            .sessions
+            .iter()
+            .filter(|(_, s)| !s.busy)
+            .map(|(id, s)| (s.last_used, id.clone()))
+            .collect();
+        idle.sort();
+
+        let mut idle = idle.into_iter();
+        while self.sessions.len() > self.limits.max_sessions {
+            match idle.next() {
+                Some((_, id)) => {
+                    self.sessions.remove(&id);
+                }
+                None => break,
+            }
+        }
+
+        let mut cache_bytes = self.cache_bytes();
+        for (_, id) in idle {
+            if cache_bytes <= self.limits.max_cache_bytes {
+                break;
+            }
+            if let Some(session) = self.sessions.get_mut(&id) {
+                cache_bytes -= session.kv.size_in_bytes();
+                session.kv = KvSnapshot::default();
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> SessionStore {
+        SessionStore::new(SessionLimits {
+            ttl: Duration::from_secs(60),
+            max_sessions: 8,
+            max_cache_bytes: usize::MAX,
+        })
+    }
+
+    #[test]
+    fn sessions_of_other_keys_are_not_found() {
+        let mut sessions = store();
+        let id = sessions.create(Some("ka"), "m".to_string(), vec![]);
+        for other in [Some("kb"), None] {
+            assert!(sessions.get(&id, other).is_none());
+            assert_eq!(sessions.fork(&id, other), Err(SessionError::NotFound));
+            assert_eq!(sessions.begin_turn(&id, other).err(), Some(SessionError::NotFound));
+            assert!(!sessions.remove(&id, other));
+        }
+
+        let fork = sessions.fork(&id, Some("ka")).unwrap();
+        assert_eq!(sessions.get(&fork, Some("ka")).unwrap().owner.as_deref(), Some("ka"));
+        assert!(sessions.get(&fork, Some("kb")).is_none());
+        assert!(sessions.remove(&id, Some("ka")));
+    }
+}
@@ -0,0 +1,109 @@
+// Stop string detection over the decoded stream, where a stop string may span several tokens
+
+/// Holds back the tail of the output that could still turn into a stop string, so that
+/// nothing of a stop string ever reaches the client.
+#[derive(Debug, Clone, Default)]
+pub struct StopMatcher {
+    stop: Vec<String>,
+    // decoded text not handed out yet
+    pending: String,
+}
+
+impl StopMatcher {
+    pub fn new(stop: Vec<String>) -> Self {
+        StopMatcher { stop: stop.into_iter().filter(|s| !s.is_empty()).collect(), pending: String::new() }
+    }
+
+    /// Appends a decoded fragment. Returns the text that is safe to emit and whether a stop string
+    /// was found, in which case the stop string and everything after it is dropped.
+    pub fn push(&mut self, fragment: &str) -> (String, bool) {
+        self.pending += fragment;
+        if let Some(pos) = self.stop.iter().filter_map(|s| self.pending.find(s.as_str())).min() {
+            self.pending.truncate(pos);
+            return (std::mem::take(&mut self.pending), true);
+        }
+        // keep the longest suffix that is the beginning of a stop string
+        let held = self
+            .pending
+            .char_indices()
+            .map(|(i, _)| i)
+            .find(|&i| self.stop.iter().any(|s| s.starts_with(&self.pending[i..])))
+            .unwrap_or(self.pending.len());
+        let rest = self.pending.split_off(held);
+        (std::mem::replace(&mut self.pending, rest), false)
+    }
+
+    /// The held back text, once the generation ended for another reason.
+    pub fn flush(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(stop: &[&str]) -> StopMatcher {
+        StopMatcher::new(stop.iter().map(|s| s.to_string()).collect())
+    }
+
+    #[test]
+    fn stop_string_split_over_fragments() {
+        let mut stop = matcher(&["</answer>"]);
+        assert_eq!(stop.push("42 </"), ("42 ".to_string(), false));
+        assert_eq!(stop.push("ans"), (String::new(), false));
+        assert_eq!(stop.push("wer> and more"), (String::new(), true));
+        assert_eq!(stop.flush(), "");
+    }
+
+    #[test]
+    fn partial_match_that_is_no_stop_string_is_released() {
+        let mut stop = matcher(&["END"]);
+        assert_eq!(stop.push("the EN"), ("the ".to_string(), false));
+        assert_eq!(stop.push("D"), (String::new(), true));
+
+        let mut stop = matcher(&["END"]);
+        assert_eq!(stop.push("the EN"), ("the ".to_string(), false));
+        assert_eq!(stop.push("TRY"), ("ENTRY".to_string(), false));
+        // a held back prefix may start another one
+        assert_eq!(stop.push(" EEN"), (" E".to_string(), false));
+        assert_eq!(stop.push("D"), (String::new(), true));
+    }
+
+    #[test]
+    fn earliest_stop_string_wins() {
+        let mut stop = matcher(&["world", "lo"]);
+        assert_eq!(stop.push("hello world"), ("hel".to_string(), true));
+
+        // by where it starts, not which one is complete first or listed first
+        let mut stop = matcher(&["bc", "abcd"]);
+        assert_eq!(stop.push("xab"), ("x".to_string(), false));
+        assert_eq!(stop.push("cd"), (String::new(), true));
+    }
+
+    #[test]
+    fn multibyte_text() {
+        let mut stop = matcher(&["日本"]);
+        assert_eq!(stop.push("ça va 日"), ("ça va ".to_string(), false));
+        assert_eq!(stop.push("曜"), ("日曜".to_string(), false));
+        assert_eq!(stop.push("日本語"), (String::new(), true));
+
+        let mut stop = matcher(&["🙂!"]);
+        assert_eq!(stop.push("é🙂"), ("é".to_string(), false));
+        assert_eq!(stop.push("?"), ("🙂?".to_string(), false));
+    }
+
+    #[test]
+    fn flush_after_another_end_returns_the_held_text() {
+        let mut stop = matcher(&["###"]);
+        assert_eq!(stop.push("done #"), ("done ".to_string(), false));
+        assert_eq!(stop.push("#"), (String::new(), false));
+        assert_eq!(stop.flush(), "##");
+        assert_eq!(stop.flush(), "");
+
+        let mut stop = matcher(&[]);
+        assert_eq!(stop.push("no stop strings"), ("no stop strings".to_string(), false));
+        assert_eq!(stop.flush(), "");
+    }
+}
@@ -1,120 +1,287 @@
 #![deny(warnings)]
+// the chain of warp filters in `routes` nests deeper than the default limit
+#![recursion_limit = "256"]
 
 // use std::{io::Write, vec};
-use clap::Parser;
 
-use serde_derive::{Deserialize, Serialize};
 // use std::sync::{Arc, Mutex};
 use std::sync::Arc; // Use Arc for thread-safe reference counting
-use tokio::sync::Mutex; // Use Tokio's async Mutex
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use warp::Filter;
 use warp::http::StatusCode;
 use warp::reject::Reject;
 use warp::reply::{self, Reply};
-use std::fmt;
+
+use llm::auth::AuthError;
+use llm::error::LlmError;
 
 pub mod llm {
     pub mod quantized_qwen2_copy;
-    pub mod llm; 
+    #[allow(clippy::module_inception)]
+    pub mod llm;
     pub mod llm_ops;
+    pub mod params;
+    pub mod chat;
+    pub mod sessions;
+    pub mod engine;
+    pub mod stop;
+    pub mod error;
+    pub mod registry;
+    pub mod pool;
+    pub mod kv_cache;
+    pub mod cancel;
+    pub mod metrics;
+    pub mod auth;
+    pub mod prefix_cache;
 }
 
-
-#[derive(Deserialize, Serialize)]
-struct Prompt {
-    prompt: String,
-    temperature: u32,
-    generated: Option<String>,
+pub mod api {
+    pub mod routes;
+    pub mod handlers;
+    pub mod models;
+    pub mod state;
 }
 
-// Define the custom ServerError type
+
+// Errors of the model layer travel as rejections and are mapped to a status in `handle_rejection`
+impl Reject for LlmError {}
+impl Reject for AuthError {}
+
+// Rejection for requests that clash with the current state of a resource, e.g. a busy session
 #[derive(Debug)]
-struct ServerError {
+struct Conflict {
     message: String,
 }
 
-// Implement the Reject trait for ServerError
-impl Reject for ServerError {}
+impl Reject for Conflict {}
 
-// Implement Display for ServerError for better error messages
-impl fmt::Display for ServerError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.message)
-    }
-}
+// A resource a handler did not find, e.g. an unknown session. Unlike `warp::reject::not_found` it
+// is not outranked by the 405 of the routes with the same path and another method.
+#[derive(Debug)]
+struct NotFound;
+
+impl Reject for NotFound {}
 
 // Error handler function to convert rejections into HTTP responses
-async fn handle_rejection(err: warp::Rejection) -> Result<impl Reply, warp::Rejection> {
-    if let Some(server_error) = err.find::<ServerError>() {
-        // Return a JSON response with the error message and a 500 status code
+async fn handle_rejection(err: warp::Rejection) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Some(llm_error) = err.find::<LlmError>() {
+        // Return a JSON response with the error message, its code and a matching status
+        let status = match llm_error {
+            LlmError::InvalidParams(_) => StatusCode::BAD_REQUEST,
+            LlmError::ContextOverflow { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            LlmError::Tokenizer(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            LlmError::Unavailable(_)
+            | LlmError::QueueFull { .. }
+            | LlmError::QueueTimeout { .. }
+            | LlmError::KvCacheFull { .. } => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            LlmError::UnknownModel(_) => StatusCode::NOT_FOUND,
+            LlmError::ModelLoad(_) | LlmError::Inference(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
         let json = warp::reply::json(&serde_json::json!({
-            "error": server_error.message
+            "error": llm_error.to_string(),
+            "code": llm_error.code()
         }));
-        Ok(reply::with_status(json, StatusCode::INTERNAL_SERVER_ERROR))
-    } else {
-        // For other errors, return a generic 500 error
+        let response = reply::with_status(json, status);
+        // tell clients turned away for load when to come back
+        match llm_error.retry_after() {
+            Some(secs) => Ok(reply::with_header(response, "retry-after", secs.to_string()).into_response()),
+            None => Ok(response.into_response()),
+        }
+    } else if let Some(auth_error) = err.find::<AuthError>() {
+        let status = match auth_error {
+            AuthError::Missing | AuthError::Invalid => StatusCode::UNAUTHORIZED,
+            AuthError::Forbidden | AuthError::RemoteAdmin => StatusCode::FORBIDDEN,
+            AuthError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+        };
         let json = warp::reply::json(&serde_json::json!({
-            "error": "Internal Server Error"
+            "error": auth_error.to_string(),
+            "code": auth_error.code()
         }));
-        Ok(reply::with_status(json, StatusCode::INTERNAL_SERVER_ERROR))
+        let mut response = reply::with_status(json, status).into_response();
+        let headers = response.headers_mut();
+        if status == StatusCode::UNAUTHORIZED {
+            headers.insert("www-authenticate", warp::http::HeaderValue::from_static("Bearer"));
+        }
+        if let Some(secs) = auth_error.retry_after() {
+            headers.insert("retry-after", secs.into());
+        }
+        Ok(response)
+    } else if let Some(conflict) = err.find::<Conflict>() {
+        Ok(error_reply(&conflict.message, "conflict", StatusCode::CONFLICT))
+    } else if let Some(body_error) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        // e.g. a missing field or an unknown message role
+        Ok(error_reply(&body_error.to_string(), "invalid_body", StatusCode::BAD_REQUEST))
+    } else if err.is_not_found() || err.find::<NotFound>().is_some() {
+        Ok(error_reply("Not Found", "not_found", StatusCode::NOT_FOUND))
+    } else if let Some(e) = err.find::<warp::reject::PayloadTooLarge>() {
+        // a body over the `content_length_limit` of the route
+        Ok(error_reply(&e.to_string(), "payload_too_large", StatusCode::PAYLOAD_TOO_LARGE))
+    } else if let Some(e) = err.find::<warp::reject::LengthRequired>() {
+        Ok(error_reply(&e.to_string(), "length_required", StatusCode::LENGTH_REQUIRED))
+    } else if let Some(e) = err.find::<warp::reject::UnsupportedMediaType>() {
+        Ok(error_reply(&e.to_string(), "unsupported_media_type", StatusCode::UNSUPPORTED_MEDIA_TYPE))
+    } else if let Some(e) = err.find::<warp::reject::MissingHeader>() {
+        Ok(error_reply(&e.to_string(), "missing_header", StatusCode::BAD_REQUEST))
+    } else if let Some(e) = err.find::<warp::reject::InvalidHeader>() {
+        Ok(error_reply(&e.to_string(), "invalid_header", StatusCode::BAD_REQUEST))
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        Ok(error_reply(&e.to_string(), "invalid_query", StatusCode::BAD_REQUEST))
+    } else if let Some(e) = err.find::<warp::reject::MethodNotAllowed>() {
+        // warp reports this when a path matched under another method only, so it comes after not found
+        Ok(error_reply(&e.to_string(), "method_not_allowed", StatusCode::METHOD_NOT_ALLOWED))
+    } else {
+        // For other errors, return a generic 500 error
+        Ok(error_reply("Internal Server Error", "internal_error", StatusCode::INTERNAL_SERVER_ERROR))
+    }
+}
+
+// A JSON error body in the `{"error", "code"}` shape the other errors use
+fn error_reply(message: &str, code: &str, status: StatusCode) -> warp::reply::Response {
+    let json = warp::reply::json(&serde_json::json!({
+        "error": message,
+        "code": code
+    }));
+    reply::with_status(json, status).into_response()
+}
+
+// With --tracing, spans (down to the layers of the model) go to a Chrome trace file,
+// trace-<timestamp>.json, and spans and events at info level are logged as JSON lines.
+// The trace is written out when the returned guard is dropped.
+fn init_tracing(args: &llm::llm::Args) -> Option<tracing_chrome::FlushGuard> {
+    use tracing_subscriber::prelude::*;
+    use tracing_subscriber::filter::{LevelFilter, Targets};
+
+    if !args.tracing {
+        return None;
     }
+    // the model and its layers, leaving out the internals of hyper
+    let profiled = Targets::new()
+        .with_target("llm_v1", LevelFilter::TRACE)
+        .with_target("candle_transformers", LevelFilter::TRACE)
+        .with_target("warp", LevelFilter::INFO);
+    let (chrome_layer, guard) = tracing_chrome::ChromeLayerBuilder::new().include_args(true).build();
+    let json_layer = tracing_subscriber::fmt::layer()
+        .json()
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+        .with_filter(LevelFilter::INFO);
+    tracing_subscriber::registry().with(chrome_layer.with_filter(profiled)).with(json_layer).init();
+    Some(guard)
+}
+
+// Startup failures are reported instead of panicking
+fn exit_with(e: impl std::fmt::Display) -> ! {
+    eprintln!("{}", e);
+    std::process::exit(1)
+}
+
+// Loads the default model and runs the CLI prompt through it once; /readyz answers 200 afterwards
+async fn warm_up(models: Arc<llm::pool::ModelPool>, args: Arc<llm::llm::Args>, ready: Arc<AtomicBool>) {
+    // let (mut model, mut tos) = llm::llm_ops::build_model(&args).unwrap(); 
+    let engine = models.engine(None).await.unwrap_or_else(|e| exit_with(e));
+    println!("model: {} ({})", engine.model().id, engine.model().gguf.display());
+
+    let str_output = {
+        let request = llm::engine::GenerateRequest {
+            request_id: "warm-up".to_string(),
+            prompt: llm::llm_ops::format_prompt(&args, engine.model().template, None),
+            config: llm::params::GenerationConfig::from_args(&args),
+            kv: llm::sessions::KvSnapshot::default(),
+            on_token: Box::new(|_| true),
+            on_queued: None,
+            on_usage: None,
+            cancel: Default::default(),
+        };
+        engine.generate(request).await.unwrap_or_else(|e| exit_with(e)).0.text
+    };
+    println!("first str_output: {:#?}", str_output);
+    ready.store(true, Ordering::Relaxed);
 }
 
 #[tokio::main]
 async fn main() {
     println!("Testing LLM text gen!");
-    let args = llm::llm::Args::parse();
+    let args = llm::llm::Args::load().unwrap_or_else(|e| exit_with(e));
     println!("args: {:#?}", args);
+    let _tracing = init_tracing(&args);
 
     llm::llm_ops::print_setup(&args);
 
-    // let (mut model, mut tos) = llm::llm_ops::build_model(&args).unwrap(); 
-    let (model, tos) = llm::llm_ops::build_model(&args).unwrap(); 
-    
-    // Wrap model, tos, and args in Arc<Mutex<...>> for thread-safe sharing
-    let model = Arc::new(Mutex::new(model));
-    let tos = Arc::new(Mutex::new(tos));
+    // --registry lists local files only; without it the --which model comes from the hub
+    let registry = llm::registry::ModelRegistry::from_args(&args).unwrap_or_else(|e| exit_with(e));
+    let default = registry.select(args.model_id.as_deref()).unwrap_or_else(|e| exit_with(e));
+    if let Some(tokens) = args.bench_decode {
+        llm::llm_ops::bench_decode(default, &args, tokens).unwrap_or_else(|e| exit_with(e));
+        return;
+    }
+    let default = default.id.clone();
     let args = Arc::new(args);
 
-    let str_output = {
-        let mut model = model.lock().await; // Use async lock
-        let mut tos = tos.lock().await;    // Use async lock
-        llm::llm_ops::run_model(&mut model, &mut tos, &args, None).unwrap()
-    };
-    println!("first str_output: {:#?}", str_output);
+    // Each model gets an engine thread that owns it and batches its concurrent requests;
+    // the default model is loaded right away (while the server already answers probes),
+    // the others on their first request
+    let models = Arc::new(llm::pool::ModelPool::new(registry, default, args.clone()));
+    let ready = Arc::new(AtomicBool::new(false));
+    tokio::spawn(warm_up(models.clone(), args.clone(), ready.clone()));
 
-    // POST /employees/:rate  {"name":"Sean","rate":2}
-    let promote = warp::post()
-        .and(warp::path("generate"))
-        .and(warp::body::content_length_limit(1024 * 16))
-        .and(warp::body::json())
-        .and(warp::any().map(move || model.clone())) // Clone Arc for each request
-        .and(warp::any().map(move || tos.clone()))   // Clone Arc for each request
-        .and(warp::any().map(move || args.clone()))  // Clone Arc for each request
-        .and_then(
-            |mut prompt: Prompt, model: Arc<Mutex<_>>, tos: Arc<Mutex<_>>, args: Arc<_>| async move {
-                let mut model = model.lock().await; // Async lock
-                let mut tos = tos.lock().await;     // Async lock
-                match llm::llm_ops::run_model(&mut model, &mut tos, &args, Some(&prompt.prompt)) {
-                    Ok(output) => {
-                        prompt.generated = Some(output);
-                        Ok::<_, warp::Rejection>(warp::reply::json(&prompt))
-                    }
-                    Err(e) => {
-                        eprintln!("Error running model: {}", e);
-                        Err(warp::reject::custom(ServerError {
-                                message: format!("Error running model: {}", e),
-                        }))
-                    }
-                }
-            },
-        );
-
-    // Add the rejection handler to the Warp filter chain
-    let routes = promote.recover(handle_rejection);
-
-    println!("Server started at http://localhost:8000");
-    warp::serve(routes).run(([127, 0, 0, 1], 8000)).await
-}
+    let sessions = llm::sessions::SessionStore::new(llm::sessions::SessionLimits {
+        ttl: std::time::Duration::from_secs(args.session_ttl_secs),
+        max_sessions: args.max_sessions,
+        max_cache_bytes: args.session_cache_mb * 1024 * 1024,
+    });
+    let sessions = Arc::new(std::sync::Mutex::new(sessions));
+
+    let idle_models = models.clone();
+    let model_idle = std::time::Duration::from_secs(args.model_idle_secs);
+    tokio::spawn(async move {
+        let period = model_idle.clamp(std::time::Duration::from_secs(1), std::time::Duration::from_secs(60));
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            idle_models.unload_idle(model_idle);
+        }
+    });
+
+    let in_flight = Arc::new(llm::cancel::InFlight::default());
+    let addr = std::net::SocketAddr::new(args.host, args.port);
+    let tls = args.tls_cert.clone().zip(args.tls_key.clone());
+    let keys = args.api_keys.as_ref().map(|path| {
+        let keys = llm::auth::ApiKeys::load(std::path::Path::new(path)).unwrap_or_else(|e| exit_with(e));
+        Arc::new(keys)
+    });
+    let state = api::state::AppState { models, args, sessions, in_flight, ready, keys };
 
+    // Add the rejection handler to the Warp filter chain, and count every response by its status
+    let count_status = warp::log::custom(|info| {
+        llm::metrics::METRICS.requests.with_label_values(&[info.status().as_str()]).inc();
+    });
+    // each request gets a span (method, path, status) that the engine's spans nest under
+    let routes = api::routes::routes(state)
+        .recover(handle_rejection)
+        .with(count_status)
+        .with(warp::trace::request());
+
+    // stop on Ctrl-C instead of being killed, so the trace file gets written
+    let shutdown = async {
+        let _ = tokio::signal::ctrl_c().await;
+        println!("shutting down");
+    };
+    match tls {
+        Some((cert, key)) => {
+            let server = warp::serve(routes).tls().cert_path(&cert).key_path(&key);
+            let (addr, server) = server
+                .try_bind_with_graceful_shutdown(addr, shutdown)
+                .unwrap_or_else(|e| exit_with(format!("{} / {}: {}", cert, key, e)));
+            println!("Server started at https://{}", addr);
+            server.await
+        }
+        None => {
+            let server = warp::serve(routes);
+            let (addr, server) = server.try_bind_with_graceful_shutdown(addr, shutdown).unwrap_or_else(|e| exit_with(e));
+            println!("Server started at http://{}", addr);
+            server.await
+        }
+    }
+}